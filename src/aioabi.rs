@@ -1,8 +1,12 @@
+//! Kernel AIO ABI definitions.
+//!
+//! These are needed to fill in the iocb for user-defined operations
+//! (see `raw::Op`).
 #![allow(dead_code)]
 extern crate std;
 extern crate libc;
 
-use libc::{uint16_t, uint32_t, uint64_t, int64_t, c_long, c_int, size_t};
+use libc::{c_long, c_int, size_t};
 pub use libc::timespec;
 use std::mem::zeroed;
 use std::default::Default;
@@ -12,26 +16,26 @@ use std::default::Default;
 #[repr(C)]
 #[allow(non_camel_case_types)]
 pub struct Struct_iocb {
-    pub data: uint64_t,             // ends up in io_event.data
+    pub data: u64,                  // ends up in io_event.data
 
-    pub key: uint32_t,
-    pub aio_reserved1: uint32_t,
+    pub key: u32,
+    pub aio_rw_flags: u32,          // RWF_* flags
 
-    pub aio_lio_opcode: uint16_t,
-    pub aio_reqprio: uint16_t,
-    pub aio_fildes: uint32_t,
+    pub aio_lio_opcode: u16,
+    pub aio_reqprio: u16,
+    pub aio_fildes: u32,
 
     // PREAD/PWRITE -> void *
     // PREADV/PWRITEV -> iovec
-    pub aio_buf: uint64_t,
-    pub aio_count: uint64_t,        // bytes or iovec entries
-    pub aio_offset: uint64_t,
+    pub aio_buf: u64,
+    pub aio_count: u64,             // bytes or iovec entries
+    pub aio_offset: u64,
 
-    pub aio_reserved2: uint64_t,
+    pub aio_reserved2: u64,
 
-    pub aio_flags: uint32_t,
+    pub aio_flags: u32,
 
-    pub aio_resfd: uint32_t,
+    pub aio_resfd: u32,
 }
 
 impl Default for Struct_iocb {
@@ -44,27 +48,42 @@ impl Default for Struct_iocb {
 }
 
 #[repr(C)]
+#[allow(non_camel_case_types)]
 pub enum Iocmd {
     IO_CMD_PREAD = 0,
     IO_CMD_PWRITE = 1,
     IO_CMD_FSYNC = 2,
     IO_CMD_FDSYNC = 3,
     // IOCB_CMD_PREADX = 4,
-    // IOCB_CMD_POLL = 5,
+    IO_CMD_POLL = 5,
     IO_CMD_NOOP = 6,
     IO_CMD_PREADV = 7,
     IO_CMD_PWRITEV = 8,
 }
 
 pub const IOCB_FLAG_RESFD : u32 = 1 << 0;
+pub const IOCB_FLAG_IOPRIO : u32 = 1 << 1;
+
+// IO priority classes for aio_reqprio, from linux/include/uapi/linux/ioprio.h
+pub const IOPRIO_CLASS_SHIFT : u16 = 13;
+pub const IOPRIO_CLASS_RT : u16 = 1;
+pub const IOPRIO_CLASS_BE : u16 = 2;
+pub const IOPRIO_CLASS_IDLE : u16 = 3;
+
+// Flags for aio_rw_flags, from linux/include/uapi/linux/fs.h
+pub const RWF_HIPRI : u32 = 0x00000001;
+pub const RWF_DSYNC : u32 = 0x00000002;
+pub const RWF_SYNC : u32 = 0x00000004;
+pub const RWF_NOWAIT : u32 = 0x00000008;
+pub const RWF_APPEND : u32 = 0x00000010;
 
 #[repr(C)]
 #[allow(non_camel_case_types)]
 pub struct Struct_io_event {
-    pub data: uint64_t,
-    pub obj: uint64_t,
-    pub res: int64_t,
-    pub res2: int64_t,
+    pub data: u64,
+    pub obj: u64,
+    pub res: i64,
+    pub res2: i64,
 }
 
 impl Default for Struct_io_event {
@@ -73,6 +92,25 @@ impl Default for Struct_io_event {
     }
 }
 
+// Header of the completion ring the kernel maps at the address
+// returned by io_setup; the io_event array follows it directly.
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct Struct_aio_ring {
+    pub id: u32,                    // kernel internal index number
+    pub nr: u32,                    // number of io_events
+    pub head: u32,                  // consumer index, written by userspace
+    pub tail: u32,                  // producer index, written by the kernel
+
+    pub magic: u32,
+    pub compat_features: u32,
+    pub incompat_features: u32,
+    pub header_length: u32,         // size of aio_ring
+}
+
+pub const AIO_RING_MAGIC : u32 = 0xa10a10a1;
+pub const AIO_RING_INCOMPAT_FEATURES : u32 = 0;
+
 #[allow(non_camel_case_types)]
 pub enum Struct_io_context { }
 #[allow(non_camel_case_types)]
@@ -82,11 +120,81 @@ pub type io_context_t = *mut Struct_io_context;
 //unsafe impl Send for *mut Struct_iocb {}
 
 #[repr(C)]
+#[derive(Copy, Clone)]
 pub struct Struct_iovec {
     pub iov_base: *mut u8,
     pub iov_len: size_t,
 }
 
+// Direct syscall interface to kernel AIO. These follow libaio's
+// convention of returning -errno on failure, rather than setting
+// errno, so the two implementations are interchangeable.
+#[cfg(not(feature = "libaio"))]
+fn sysret(r: c_long) -> c_int {
+    if r < 0 {
+        -std::io::Error::last_os_error().raw_os_error().unwrap_or(libc::EINVAL)
+    } else {
+        r as c_int
+    }
+}
+
+/// Create an AIO context for up to `maxevents` operations.
+///
+/// # Safety
+///
+/// `ctxp` must point to an `io_context_t` initialized to null.
+#[cfg(not(feature = "libaio"))]
+pub unsafe fn io_setup(maxevents: c_int, ctxp: *mut io_context_t) -> c_int {
+    sysret(libc::syscall(libc::SYS_io_setup, maxevents as c_long, ctxp))
+}
+
+/// Destroy an AIO context, cancelling or waiting for its operations.
+///
+/// # Safety
+///
+/// `ctx` must not be used afterwards.
+#[cfg(not(feature = "libaio"))]
+pub unsafe fn io_destroy(ctx: io_context_t) -> c_int {
+    sysret(libc::syscall(libc::SYS_io_destroy, ctx))
+}
+
+/// Submit `nr` operations.
+///
+/// # Safety
+///
+/// `ios` must point to `nr` valid iocbs. They, and any buffers they
+/// refer to, must remain valid until the operations complete.
+#[cfg(not(feature = "libaio"))]
+pub unsafe fn io_submit(ctx: io_context_t, nr: c_long, ios: *mut *mut Struct_iocb) -> c_int {
+    sysret(libc::syscall(libc::SYS_io_submit, ctx, nr, ios))
+}
+
+/// Cancel an outstanding operation.
+///
+/// # Safety
+///
+/// `iocb` must be an iocb submitted to `ctx`, and `evt` must be
+/// writable.
+#[cfg(not(feature = "libaio"))]
+pub unsafe fn io_cancel(ctx: io_context_t, iocb: *mut Struct_iocb, evt: *mut Struct_io_event) -> c_int {
+    sysret(libc::syscall(libc::SYS_io_cancel, ctx, iocb, evt))
+}
+
+/// Wait for between `min_nr` and `nr` completions.
+///
+/// # Safety
+///
+/// `events` must have room for `nr` events, and `timeout` must be
+/// null or point to a valid timespec.
+#[cfg(not(feature = "libaio"))]
+pub unsafe fn io_getevents(ctx_id: io_context_t, min_nr: c_long,
+                           nr: c_long, events: *mut Struct_io_event,
+                           timeout: *mut timespec) -> c_int {
+    sysret(libc::syscall(libc::SYS_io_getevents, ctx_id, min_nr, nr, events, timeout))
+}
+
+// libaio's wrappers, enabled with the "libaio" feature.
+#[cfg(feature = "libaio")]
 #[link(name = "aio")]
 extern "C" {
     pub fn io_queue_init(maxevents: c_int, ctxp: *mut io_context_t) -> c_int;
@@ -110,5 +218,18 @@ mod test {
         // Check against kernel ABI
         assert!(size_of::<super::Struct_io_event>() == 32);
         assert!(size_of::<super::Struct_iocb>() == 64);
+        assert!(size_of::<super::Struct_aio_ring>() == 32);
+    }
+
+    #[test]
+    fn test_setup() {
+        let mut ctx = ::std::ptr::null_mut();
+
+        // Errors are returned as -errno
+        assert_eq!(unsafe { super::io_setup(0, &mut ctx) }, -::libc::EINVAL);
+
+        assert_eq!(unsafe { super::io_setup(10, &mut ctx) }, 0);
+        assert!(!ctx.is_null());
+        assert_eq!(unsafe { super::io_destroy(ctx) }, 0);
     }
 }
@@ -1,5 +1,5 @@
 //! Aligned memory buffers for Direct IO.
-use std::rt::heap;
+use std::alloc::{self, Layout};
 use std::ptr;
 use std::slice;
 
@@ -22,11 +22,34 @@ fn ispower2(n: usize) -> bool {
     (n & (n - 1)) == 0
 }
 
+// The allocator can't allocate 0 bytes, so an empty buffer is just an
+// aligned dangling pointer.
+unsafe fn allocate(sz: usize, align: usize) -> *mut u8 {
+    if sz == 0 {
+        return align as *mut u8
+    }
+    match Layout::from_size_align(sz, align) {
+        Err(_) => ptr::null_mut(),
+        Ok(layout) => alloc::alloc(layout),
+    }
+}
+
+unsafe fn deallocate(ptr: *mut u8, sz: usize, align: usize) {
+    if sz != 0 {
+        alloc::dealloc(ptr, Layout::from_size_align_unchecked(sz, align))
+    }
+}
+
 unsafe fn realloc(ptr: *mut u8, oldsz: usize, sz: usize, align: usize) -> *mut u8 {
-    if heap::reallocate_inplace(ptr, oldsz, sz, align) >= sz {
-        ptr
+    if oldsz == 0 {
+        allocate(sz, align)
+    } else if sz == 0 {
+        deallocate(ptr, oldsz, align);
+        align as *mut u8
+    } else if Layout::from_size_align(sz, align).is_err() {
+        ptr::null_mut()
     } else {
-        heap::reallocate(ptr, oldsz, sz, align)
+        alloc::realloc(ptr, Layout::from_size_align_unchecked(oldsz, align), sz)
     }
 }
 
@@ -34,8 +57,10 @@ impl AlignedBuf {
     /// Allocate some uninitialized memory. No bytes are valid as a
     /// result of this. Returns `None` on allocation failure.
     ///
-    /// # Preconditions
-    /// `align` must be a power of 2, and greater than 0.
+    /// # Safety
+    ///
+    /// The contents are uninitialized, so must be written before
+    /// they're read. `align` must be a power of 2, and greater than 0.
     pub unsafe fn alloc_uninit(size: usize, align: usize) -> Option<AlignedBuf> {
         assert!(align > 0);
         assert!(ispower2(align));
@@ -43,12 +68,12 @@ impl AlignedBuf {
         let sz = (size + align - 1) & !(align - 1);
         assert!(sz >= size);
         assert!(sz % align == 0);
-        let p = heap::allocate(sz, align);
+        let p = allocate(sz, align);
 
         if p.is_null() {
             None
         } else {
-            Some(AlignedBuf { buf: p, len: sz, valid: 0, align: align })
+            Some(AlignedBuf { buf: p, len: sz, valid: 0, align })
         }
     }
 
@@ -87,8 +112,12 @@ impl AlignedBuf {
     /// Extend a buffer to `size` bytes, leaving the added storage
     /// uninitialized. Returns false if the allocation fails. `size`
     /// is rounded up to the alignment.
+    ///
+    /// # Safety
+    ///
+    /// The added storage must be written before it's read.
     pub unsafe fn extend_uninit(&mut self, size: usize) -> bool {
-        let sz = (size + self.align - 1) & (self.align - 1);
+        let sz = (size + self.align - 1) & !(self.align - 1);
 
         assert!(sz >= self.len);
         if sz == self.len {
@@ -126,7 +155,7 @@ impl AlignedBuf {
 
     /// Shrink a buffer. `size` is rounded up to the alignment.
     pub fn shrink(&mut self, size: usize) -> bool {
-        let sz = (size + self.align - 1) & (self.align - 1);
+        let sz = (size + self.align - 1) & !(self.align - 1);
         assert!(sz <= self.len);
 
         unsafe {
@@ -136,7 +165,9 @@ impl AlignedBuf {
             if ok {
                 self.buf = p;
                 self.len = sz;
-                self.valid = sz;
+                if self.valid > sz {
+                    self.valid = sz
+                }
             };
 
             ok
@@ -145,21 +176,33 @@ impl AlignedBuf {
 
     pub fn as_slice(&self) -> &[u8] { self.wrbuf() }
     
+    /// Pointer to the start of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// Only the first `valid()` bytes are initialized.
     pub unsafe fn as_ptr(&self) -> *const u8 {
         self.buf as *const u8
     }
 
+    /// Mutable pointer to the start of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// Only the first `valid()` bytes are initialized, and writes
+    /// must stay within `len()` bytes.
     pub unsafe fn as_mut_ptr(&mut self) -> *mut u8 {
         self.buf
     }
 
     pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.len == 0 }
     pub fn valid(&self) -> usize { self.valid }
 }
 
 impl Drop for AlignedBuf {
     fn drop(&mut self) {
-        unsafe { heap::deallocate(self.buf, self.len, self.align) }
+        unsafe { deallocate(self.buf, self.len, self.align) }
     }
 }
 
@@ -173,8 +216,8 @@ impl Clone for AlignedBuf {
             match AlignedBuf::alloc_uninit(self.len, self.align) {
                 None => panic!("clone failed"),
                 Some(mut b) => {
-                    if b.valid > 0 {
-                        ptr::copy_nonoverlapping(self.buf as *const u8, b.buf, b.valid);
+                    if self.valid > 0 {
+                        ptr::copy_nonoverlapping(self.buf as *const u8, b.buf, self.valid);
                         b.valid = self.valid
                     };
                     b
@@ -187,7 +230,7 @@ impl Clone for AlignedBuf {
 impl RdBuf for AlignedBuf {
     /// Return a writable slice to the whole buffer; it may not be
     /// initialized, and so should be treated as write-only.
-    fn rdbuf<'a>(&'a mut self) -> &'a mut [u8] {
+    fn rdbuf(&mut self) -> &mut [u8] {
         assert!(self.valid <= self.len);
         unsafe { slice::from_raw_parts_mut(self.buf, self.len) }
     }
@@ -204,7 +247,7 @@ impl RdBuf for AlignedBuf {
 
 impl WrBuf for AlignedBuf {
     /// Return a read-only slice of the valid portion of the buffer.
-    fn wrbuf<'a>(&'a self) -> &'a [u8] {
+    fn wrbuf(&self) -> &[u8] {
         assert!(self.valid <= self.len);
         unsafe { slice::from_raw_parts_mut(self.buf, self.valid) }
     }
@@ -231,5 +274,32 @@ mod test {
 
         let p = alloc(17, 16);
         assert_eq!(p.as_slice().len(), 32);
+
+        let p = alloc(0, 16);
+        assert_eq!(p.as_slice().len(), 0);
+    }
+
+    #[test]
+    fn resize() {
+        let mut p = AlignedBuf::from_slice(&[1, 2, 3], 4096).unwrap();
+        assert_eq!((p.len(), p.valid()), (4096, 4096));
+        assert_eq!(unsafe { p.as_ptr() } as usize % 4096, 0);
+
+        assert!(p.extend(5000));
+        assert_eq!((p.len(), p.valid()), (8192, 8192));
+        assert_eq!(unsafe { p.as_ptr() } as usize % 4096, 0);
+        assert_eq!(&p.as_slice()[..4], &[1, 2, 3, 0]);
+
+        let q = p.clone();
+        assert_eq!(q.as_slice(), p.as_slice());
+
+        assert!(p.shrink(10));
+        assert_eq!((p.len(), p.valid()), (4096, 4096));
+        assert_eq!(&p.as_slice()[..3], &[1, 2, 3]);
+
+        assert!(p.shrink(0));
+        assert_eq!((p.len(), p.valid()), (0, 0));
+        assert!(p.extend(1));
+        assert_eq!(p.as_slice(), &[0; 4096][..]);
     }
 }
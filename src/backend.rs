@@ -0,0 +1,195 @@
+//! Backends which carry out the operations queued on a
+//! `raw::Iocontext`.
+//!
+//! The context builds a kernel iocb for each operation and hands
+//! batches of them to its backend, which mirrors the kernel's
+//! `io_submit`/`io_cancel`/`io_getevents` interface. `Kernel` uses
+//! Linux AIO; other backends can run the same iocbs some other way.
+//! `Native` is the default: with the `uring` feature it's
+//! `uring::Native`, which uses io_uring when the kernel has it, and
+//! otherwise it's `Kernel`.
+extern crate std;
+
+use std::io;
+use std::ptr;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use libc::{c_long, time_t};
+
+use aioabi as aio;
+
+/// Implementation of the AIO operations for an `Iocontext`.
+///
+/// Each iocb's `data` field must be returned unchanged in the
+/// `data` field of its completion event.
+pub trait Backend {
+    /// Start some operations, like `io_submit`. Returns the number
+    /// accepted, which may be less than all of them; if none are
+    /// accepted, returns the error for the first one. `EAGAIN` means
+    /// there are no resources to take any more right now.
+    ///
+    /// # Safety
+    ///
+    /// The iocbs, and any buffers they refer to, must remain valid
+    /// until they complete or are cancelled.
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize>;
+
+    /// Cancel an operation, like `io_cancel`. On success no
+    /// completion event is generated for it.
+    ///
+    /// # Safety
+    ///
+    /// `iocb` must have been submitted to this backend.
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()>;
+
+    /// Wait until `deadline` (forever if `None`) for at least `min`
+    /// completions, filling in up to `events.len()` of them. Returns
+    /// the number filled in, which is only less than `min` if the
+    /// deadline passed. A wait interrupted by a signal is resumed.
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize>;
+
+    /// Return completions which are available immediately, or `None`
+    /// if that can't be done more cheaply than with `getevents`.
+    fn reap(&mut self, _events: &mut [aio::Struct_io_event]) -> Option<usize> { None }
+
+    /// Called when the context creates its eventfd, before any
+    /// operation names it in `aio_resfd`. Backends which signal it
+    /// some other way than per operation can set that up here.
+    fn set_eventfd(&mut self, _fd: RawFd) -> io::Result<()> { Ok(()) }
+}
+
+/// The default backend.
+#[cfg(not(feature = "uring"))]
+pub type Native = Kernel;
+#[cfg(feature = "uring")]
+pub use uring::Native;
+
+/// Linux kernel AIO backend.
+pub struct Kernel {
+    ctx: aio::io_context_t,
+}
+
+// The context is only an identifier for kernel state.
+unsafe impl Send for Kernel {}
+
+impl Kernel {
+    /// Set up a kernel AIO context for `maxops` outstanding operations.
+    pub fn new(maxops: usize) -> io::Result<Kernel> {
+        let mut k = Kernel { ctx: ptr::null_mut() };
+        let e = unsafe { aio::io_setup(maxops as i32, &mut k.ctx) };
+
+        if e < 0 {
+            Err(io::Error::from_raw_os_error(-e))
+        } else {
+            Ok(k)
+        }
+    }
+}
+
+fn timespec_from_duration(dur: Duration) -> aio::timespec {
+    let secs = std::cmp::min(dur.as_secs(), time_t::MAX as u64);
+
+    aio::timespec { tv_sec: secs as time_t, tv_nsec: dur.subsec_nanos() as c_long }
+}
+
+// Time remaining until `deadline`, or 0 if it has passed.
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+
+    if deadline > now { deadline - now } else { Duration::from_secs(0) }
+}
+
+impl Backend for Kernel {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        let r = aio::io_submit(self.ctx, iocbs.len() as i64, iocbs.as_ptr() as *mut *mut aio::Struct_iocb);
+
+        if r < 0 {
+            Err(io::Error::from_raw_os_error(-r))
+        } else {
+            Ok(r as usize)
+        }
+    }
+
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        let mut ev = Default::default();
+        let r = aio::io_cancel(self.ctx, iocb, &mut ev);
+
+        if r < 0 {
+            Err(io::Error::from_raw_os_error(-r))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        loop {
+            let r = unsafe {
+                let mut ts = deadline.map(|d| timespec_from_duration(remaining(d)));
+                let tsp = match ts.as_mut() {
+                    None => ptr::null_mut(),
+                    Some(ts) => ts as *mut aio::timespec,
+                };
+                aio::io_getevents(self.ctx, min as i64, events.len() as i64, events.as_mut_ptr(), tsp)
+            };
+
+            if r >= 0 {
+                return Ok(r as usize)
+            } else if -r != ::libc::EINTR {
+                return Err(io::Error::from_raw_os_error(-r))
+            }
+        }
+    }
+
+    // Reap straight from the kernel's completion ring, without a
+    // syscall. Returns None if the ring isn't in a format we
+    // understand.
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        let ring = self.ctx as *mut aio::Struct_aio_ring;
+
+        unsafe {
+            if ring.is_null() ||
+                (*ring).magic != aio::AIO_RING_MAGIC ||
+                (*ring).incompat_features != aio::AIO_RING_INCOMPAT_FEATURES {
+                return None
+            }
+
+            let nr = (*ring).nr;
+            let head = &*(&(*ring).head as *const u32 as *const AtomicU32);
+            let tail = &*(&(*ring).tail as *const u32 as *const AtomicU32);
+            let ioev = ring.offset(1) as *const aio::Struct_io_event;
+
+            // Acquire pairs with the kernel's barrier before it
+            // updates tail, so the events up to tail are visible.
+            let t = tail.load(Ordering::Acquire);
+            let mut h = head.load(Ordering::Relaxed);
+            let mut n = 0;
+
+            if h >= nr || t >= nr {
+                return None
+            }
+
+            while n < events.len() && h != t {
+                events[n] = ptr::read_volatile(ioev.offset(h as isize));
+                h = (h + 1) % nr;
+                n += 1;
+            }
+
+            // Release the slots back to the kernel only after we've
+            // finished reading them.
+            head.store(h, Ordering::Release);
+
+            Some(n)
+        }
+    }
+}
+
+impl Drop for Kernel {
+    fn drop(&mut self) {
+        // Nothing useful can be done about failure here; all the
+        // operations have already been drained.
+        let _ = unsafe { aio::io_destroy(self.ctx) };
+    }
+}
@@ -3,7 +3,7 @@ extern crate std;
 /// Trait for types implementing a read buffer.
 pub trait RdBuf {
     /// Return a mutable u8 slice into some storage which need not be initialized.
-    fn rdbuf<'a>(&'a mut self) -> &'a mut [u8];
+    fn rdbuf(&mut self) -> &mut [u8];
 
     /// Called to indicate some range of the buffer was updated by the read, from [`base` .. `base`+`len`).
     fn rdupdate(&mut self, _base: usize, _len: usize) {}
@@ -12,18 +12,18 @@ pub trait RdBuf {
 /// Trait for types implementing a write buffer.
 pub trait WrBuf {
     /// Return an initialized immutable slice which is the source data for a write.
-    fn wrbuf<'a>(&'a self) -> &'a [u8];
+    fn wrbuf(&self) -> &[u8];
 }
 
 /// Wrapper for plain [u8] implementing RdBuf and WrBuf traits.
 pub type Buf<'b> = &'b mut [u8];
 
 impl<'b> RdBuf for Buf<'b> {
-    fn rdbuf(&mut self) -> &mut [u8] { *self }
+    fn rdbuf(&mut self) -> &mut [u8] { self }
 }
 
 impl<'b> WrBuf for Buf<'b> {
-    fn wrbuf(&self) -> &[u8] { *self }
+    fn wrbuf(&self) -> &[u8] { self }
 }
 
 impl RdBuf for Vec<u8> {
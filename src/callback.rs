@@ -0,0 +1,250 @@
+//! Callback-based interface to async IO.
+//!
+//! Each operation carries a closure, which is called with the result
+//! of the operation and the resources it used when the operation is
+//! reaped. This lets each request carry its own continuation, rather
+//! than having a central dispatch on the result of `raw::Iocontext`.
+extern crate std;
+
+use std::io;
+use std::time::Instant;
+use std::os::unix::io::AsRawFd;
+
+use buf::{RdBuf, WrBuf};
+use raw::{self, IoOp, Iohandle, Op, RwFlags, SubmitPolicy};
+use stats::Stats;
+use super::Offset;
+
+#[allow(clippy::type_complexity)]
+enum Callback<Wb, Rb> {
+    Pread(Box<dyn FnOnce(io::Result<usize>, Rb) + Send>),
+    Preadv(Box<dyn FnOnce(io::Result<usize>, Vec<Rb>) + Send>),
+    Pwrite(Box<dyn FnOnce(io::Result<usize>, Wb) + Send>),
+    Pwritev(Box<dyn FnOnce(io::Result<usize>, Vec<Wb>) + Send>),
+    Nobuf(Box<dyn FnOnce(io::Result<usize>) + Send>),         // fsync, fdsync, poll
+    Op(Box<dyn FnOnce(io::Result<usize>, Box<dyn Op>) + Send>),
+}
+
+type RawIoctx<Wb, Rb> = raw::Iocontext<Callback<Wb, Rb>, Wb, Rb>;
+
+/// Callback AIO context.
+///
+/// Operations are queued and submitted as with `raw::Iocontext`, but
+/// each takes a callback rather than a token. The callbacks are
+/// called from `reap` as the operations complete. Operations which
+/// are still outstanding when the context is dropped never have their
+/// callbacks called.
+pub struct Iocontext<Wb: WrBuf + Send, Rb: RdBuf + Send> {
+    ctx: RawIoctx<Wb, Rb>,
+}
+
+impl<Wb: WrBuf + Send, Rb: RdBuf + Send> Iocontext<Wb, Rb> {
+    /// Construct a new Iocontext, with up to `maxops` outstanding
+    /// operations.
+    pub fn new(maxops: usize) -> io::Result<Iocontext<Wb, Rb>> {
+        match raw::Iocontext::new(maxops) {
+            Err(e) => Err(e),
+            Ok(ctx) => Ok(Iocontext { ctx }),
+        }
+    }
+
+    /// Submit all batched operations. See `raw::Iocontext::submit`.
+    pub fn submit(&mut self) -> io::Result<usize> { self.ctx.submit() }
+
+    /// Set when operations are submitted automatically. See
+    /// `raw::Iocontext::set_submit_policy`.
+    pub fn set_submit_policy(&mut self, policy: SubmitPolicy) { self.ctx.set_submit_policy(policy) }
+
+    /// Enable or disable statistics. See `raw::Iocontext::set_stats`.
+    pub fn set_stats(&mut self, enable: bool) { self.ctx.set_stats(enable) }
+
+    /// Return a snapshot of the statistics, if enabled.
+    pub fn stats(&self) -> Option<Stats> { self.ctx.stats() }
+
+    /// Reap completed operations, calling their callbacks. This waits
+    /// until `deadline` (forever if `None`) for at least `min`
+    /// operations to complete, and returns the number of callbacks
+    /// called.
+    pub fn reap(&mut self, min: usize, deadline: Option<Instant>) -> io::Result<usize> {
+        let max = self.ctx.maxops();
+
+        self.ctx.results_with(min, max, deadline, |op, res| {
+            match op {
+                IoOp::Pread(buf, Callback::Pread(cb)) => cb(res, buf),
+                IoOp::Preadv(bufv, Callback::Preadv(cb)) => cb(res, bufv),
+                IoOp::Pwrite(buf, Callback::Pwrite(cb)) => cb(res, buf),
+                IoOp::Pwritev(bufv, Callback::Pwritev(cb)) => cb(res, bufv),
+                IoOp::Fsync(Callback::Nobuf(cb)) |
+                IoOp::Fdsync(Callback::Nobuf(cb)) |
+                IoOp::Poll(Callback::Nobuf(cb)) => cb(res),
+                IoOp::Op(op, Callback::Op(cb)) => cb(res, op),
+                IoOp::Noop => (),
+                _ => panic!("mismatched callback"),
+            }
+        })
+    }
+
+    /// Return number of batched operations.
+    pub fn batched(&self) -> usize { self.ctx.batched() }
+
+    /// Return number of submitted operations.
+    pub fn submitted(&self) -> usize { self.ctx.submitted() }
+
+    /// Total number of operations whose callbacks are yet to be called.
+    pub fn pending(&self) -> usize { self.ctx.pending() }
+
+    /// Returns true if no more operations can be queued.
+    pub fn full(&self) -> bool { self.ctx.full() }
+
+    /// Queue a pread. If the context is full the buffer and callback
+    /// are returned.
+    pub fn pread<F, C>(&mut self, file: &F, buf: Rb, off: Offset, flags: RwFlags, cb: C) -> Result<Iohandle, (Rb, C)>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>, Rb) + Send + 'static
+    {
+        if self.full() {
+            return Err((buf, cb))
+        }
+        self.ctx.pread_flags(file, buf, off, flags, Callback::Pread(Box::new(cb)))
+            .map_err(|_| panic!("not full but pread failed"))
+    }
+
+    /// Queue a preadv.
+    pub fn preadv<F, C>(&mut self, file: &F, bufv: Vec<Rb>, off: Offset, flags: RwFlags, cb: C) -> Result<Iohandle, (Vec<Rb>, C)>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>, Vec<Rb>) + Send + 'static
+    {
+        if self.full() {
+            return Err((bufv, cb))
+        }
+        self.ctx.preadv_flags(file, bufv, off, flags, Callback::Preadv(Box::new(cb)))
+            .map_err(|_| panic!("not full but preadv failed"))
+    }
+
+    /// Queue a pwrite.
+    pub fn pwrite<F, C>(&mut self, file: &F, buf: Wb, off: Offset, flags: RwFlags, cb: C) -> Result<Iohandle, (Wb, C)>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>, Wb) + Send + 'static
+    {
+        if self.full() {
+            return Err((buf, cb))
+        }
+        self.ctx.pwrite_flags(file, buf, off, flags, Callback::Pwrite(Box::new(cb)))
+            .map_err(|_| panic!("not full but pwrite failed"))
+    }
+
+    /// Queue a pwritev.
+    pub fn pwritev<F, C>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, flags: RwFlags, cb: C) -> Result<Iohandle, (Vec<Wb>, C)>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>, Vec<Wb>) + Send + 'static
+    {
+        if self.full() {
+            return Err((bufv, cb))
+        }
+        self.ctx.pwritev_flags(file, bufv, off, flags, Callback::Pwritev(Box::new(cb)))
+            .map_err(|_| panic!("not full but pwritev failed"))
+    }
+
+    /// Queue an fsync.
+    pub fn fsync<F, C>(&mut self, file: &F, cb: C) -> Result<Iohandle, C>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>) + Send + 'static
+    {
+        if self.full() {
+            return Err(cb)
+        }
+        self.ctx.fsync(file, Callback::Nobuf(Box::new(cb)))
+            .map_err(|_| panic!("not full but fsync failed"))
+    }
+
+    /// Queue an fdatasync.
+    pub fn fdsync<F, C>(&mut self, file: &F, cb: C) -> Result<Iohandle, C>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>) + Send + 'static
+    {
+        if self.full() {
+            return Err(cb)
+        }
+        self.ctx.fdsync(file, Callback::Nobuf(Box::new(cb)))
+            .map_err(|_| panic!("not full but fdsync failed"))
+    }
+
+    /// Queue a poll for `events`. The callback's result is the mask
+    /// of ready events.
+    pub fn poll<F, C>(&mut self, file: &F, events: ::libc::c_short, cb: C) -> Result<Iohandle, C>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>) + Send + 'static
+    {
+        if self.full() {
+            return Err(cb)
+        }
+        self.ctx.poll(file, events, Callback::Nobuf(Box::new(cb)))
+            .map_err(|_| panic!("not full but poll failed"))
+    }
+
+    /// Queue a user-defined operation. The callback is given the
+    /// operation back once it completes.
+    pub fn op<F, C>(&mut self, file: &F, op: Box<dyn Op>, cb: C) -> Result<Iohandle, (Box<dyn Op>, C)>
+        where F: AsRawFd, C: FnOnce(io::Result<usize>, Box<dyn Op>) + Send + 'static
+    {
+        if self.full() {
+            return Err((op, cb))
+        }
+        self.ctx.op(file, op, Callback::Op(Box::new(cb)))
+            .map_err(|_| panic!("not full but op failed"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File,OpenOptions};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use super::Iocontext;
+    use raw::RwFlags;
+
+    fn tmpfile(name: &str) -> File {
+        let tmp = TempDir::new("test").unwrap();
+        let mut path = tmp.into_path();
+
+        path.push(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    #[test]
+    fn simple() {
+        let mut io : Iocontext<Vec<u8>, Vec<u8>> = match Iocontext::new(2) {
+            Err(e) => panic!("new failed {:?}", e),
+            Ok(t) => t,
+        };
+        let file = tmpfile("callback");
+        let done = Arc::new(Mutex::new(Vec::new()));
+
+        let d = done.clone();
+        assert!(io.pwrite(&file, vec![b'x'; 40], 0, RwFlags::empty(), move |res, buf| {
+            assert_eq!(res.ok(), Some(40));
+            assert_eq!(buf.len(), 40);
+            d.lock().unwrap().push("write")
+        }).is_ok());
+
+        let d = done.clone();
+        assert!(io.fdsync(&file, move |res| {
+            assert!(res.is_ok());
+            d.lock().unwrap().push("sync")
+        }).is_ok());
+
+        // Full, so the callback comes straight back
+        assert!(io.fsync(&file, |_| panic!("unexpected call")).is_err());
+
+        assert!(io.submit().is_ok());
+        while io.pending() > 0 {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            assert!(io.reap(1, Some(deadline)).is_ok());
+        }
+
+        let mut done = done.lock().unwrap().clone();
+        done.sort();
+        assert_eq!(done, vec!["sync", "write"]);
+    }
+}
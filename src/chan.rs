@@ -4,12 +4,14 @@
 //! async results are returned through a result channel.
 extern crate std;
 
-use std::sync::mpsc::{Sender,SyncSender,Receiver,channel,sync_channel};
+use std::sync::mpsc::{Sender,SyncSender,Receiver,TryRecvError,channel,sync_channel};
+use std::sync::{Arc, Mutex};
 use std::io;
 use std::thread;
+use std::time::Instant;
 use std::os::unix::io::AsRawFd;
-use std::boxed::FnBox;
 use buf::{RdBuf, WrBuf};
+use eventfd::EventFd;
 
 use super::{FD, Offset};
 use raw;
@@ -18,6 +20,14 @@ fn eagain() -> io::Error {
     io::Error::from_raw_os_error(::libc::EAGAIN)
 }
 
+// io::Error isn't Clone, so make a copy which is as close as possible.
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
 /// IO result.
 ///
 /// Each operation returns an operation-specific value containing the
@@ -32,7 +42,7 @@ pub type IoRes<T, Wb, Rb> = (io::Result<usize>, raw::IoOp<T, Wb, Rb>);
 ///
 /// OpTx is the sender size of a channel for submitting new IO
 /// operations.
-type Callback<T, Wb, Rb> = Box<FnBox(&mut raw::Iocontext<T, Wb, Rb>, &Sender<IoRes<T, Wb, Rb>>)>;
+type Callback<T, Wb, Rb> = Box<dyn FnOnce(&mut ChanWorker<T, Wb, Rb>, &Sender<IoRes<T, Wb, Rb>>) + Send>;
 type OpTx<T, Wb, Rb> = SyncSender<Callback<T,Wb,Rb>>;
 
 /// Channel-based AIO context.
@@ -42,34 +52,57 @@ type OpTx<T, Wb, Rb> = SyncSender<Callback<T,Wb,Rb>>;
 /// needs to perform, which are returned when the operation
 /// completes. The context has a few helper methods to help form
 /// messages.
-pub struct Iocontext<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> {
-    optx: OpTx<T, Wb, Rb>,
+///
+/// If the worker thread fails, everything it has outstanding is
+/// returned through the result channel, and further requests fail
+/// with its error.
+pub struct Iocontext<T : Send + 'static, Wb : WrBuf + Send + 'static, Rb : RdBuf + Send + 'static> {
+    optx: Option<OpTx<T, Wb, Rb>>, // only None while dropping
     resrx: Receiver<IoRes<T, Wb, Rb>>,
+    wake: Arc<EventFd>,            // signalled for each request
+    error: Arc<Mutex<Option<io::Error>>>, // why the worker stopped
 }
 
-impl<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
+impl<T : Send + 'static, Wb : WrBuf + Send + 'static, Rb : RdBuf + Send + 'static> Iocontext<T, Wb, Rb> {
     /// Construct a new channel AIO context. When there are more than
     /// lowwater ops pending it will flush automatically; new
     /// operations will block when there's max or more outstanding
     /// operations (batched and submitted). Returns the submission and
-    /// results channel endpoints.
+    /// results channel endpoints. Fails with `EINVAL` unless
+    /// `0 < lowwater < max`.
     pub fn new(lowwater: usize, max: usize) -> io::Result<Iocontext<T, Wb, Rb>> {
-        assert!(lowwater > 0 && lowwater < max);
+        if lowwater == 0 || lowwater >= max {
+            return Err(io::Error::from_raw_os_error(::libc::EINVAL))
+        }
 
-        let mut ctx = try!(raw::Iocontext::new(max));
+        let mut ctx = raw::Iocontext::new(max)?;
 
         // Prepare events
-        let evfd = try!(ctx.get_evfd_stream());
+        ctx.eventfd()?;
+        let wake = match EventFd::new() {
+            Err(e) => return Err(e),
+            Ok(wake) => Arc::new(wake),
+        };
 
         let (optx, oprx) = sync_channel(max); // block requests when there are too many outstanding
         let (restx, resrx) = channel();       // don't block worker - there can't be more than requests anyway
+        let error = Arc::new(Mutex::new(None));
 
+        let (w, err) = (wake.clone(), error.clone());
         thread::spawn(move || {
-            let mut worker = ChanWorker { ctx: ctx, lowwater: lowwater };
-            worker.worker(oprx, restx, evfd)
+            let mut worker = ChanWorker { ctx, lowwater, flush: false };
+
+            if let Err(e) = worker.worker(oprx, &restx, w) {
+                *err.lock().unwrap() = Some(e);
+
+                // Return everything outstanding, so no resources are lost
+                for (op, res) in worker.ctx.shutdown(None) {
+                    let _ = restx.send((res, op));
+                }
+            }
         });
 
-        Ok(Iocontext { optx: optx, resrx: resrx })
+        Ok(Iocontext { optx: Some(optx), resrx, wake, error })
     }
 
     /// Return result channel.
@@ -77,37 +110,48 @@ impl<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
     /// This returns a Reciever endpoint for getting IO results, each
     /// of which is a tuple consisting of success/failure, and the
     /// return of the resources needed for the operation.
-    pub fn resrx<'a>(&'a self) -> &'a Receiver<IoRes<T, Wb, Rb>> {
+    pub fn resrx(&self) -> &Receiver<IoRes<T, Wb, Rb>> {
         &self.resrx
     }
 
-    /// Send a flush request. This causes all pending operations to be immediately submitted.
-    pub fn flush(&self) {
-        self.optx.send(move |ctx: &mut raw::Iocontext<T, Wb, Rb>, _: &Sender<IoRes<T, Wb, Rb>>| {
-            match ctx.submit() {
-                Ok(_) => (),
-                Err(_) => (),
-            }
-        })
+    // Send a request to the worker. This fails if the worker has
+    // stopped.
+    fn send(&self, op: Callback<T, Wb, Rb>) -> io::Result<()> {
+        match self.optx.as_ref().unwrap().send(op) {
+            Err(_) => match *self.error.lock().unwrap() {
+                Some(ref e) => Err(copy_error(e)),
+                None => Err(io::Error::from_raw_os_error(::libc::EPIPE)),
+            },
+            Ok(()) => self.wake.signal(1),
+        }
+    }
+
+    /// Send a flush request. This causes all pending operations to be
+    /// submitted immediately, or as soon as there's room for them.
+    pub fn flush(&self) -> io::Result<()> {
+        self.send(Box::new(move |w: &mut ChanWorker<T, Wb, Rb>, _: &Sender<IoRes<T, Wb, Rb>>| {
+            w.flush = true
+        }))
     }
 
-    fn sendhelper<F: AsRawFd>(&self, file: &F,
-                              func: FnBox(&mut raw::Iocontext<T, Wb, Rb>, FD) -> Result<(), raw::IoOp<T, Wb, Rb>>) {
-        let fd = FD::new(file);
+    fn sendhelper<F, Q>(&self, file: &F, func: Q) -> io::Result<()>
+        where F: AsRawFd, Q: FnOnce(&mut raw::Iocontext<T, Wb, Rb>, FD) -> Result<raw::Iohandle, raw::IoOp<T, Wb, Rb>> + Send + 'static
+    {
+        let fd = FD(file.as_raw_fd());
 
-        self.optx.send(move |ctx: &mut raw::Iocontext<T, Wb, Rb>, restx: &Sender<IoRes<T, Wb, Rb>>| {
-            match func(ctx, fd) {
+        self.send(Box::new(move |w: &mut ChanWorker<T, Wb, Rb>, restx: &Sender<IoRes<T, Wb, Rb>>| {
+            match func(&mut w.ctx, fd) {
                 Ok(_) => (),
-                Err(r) => restx.send((Err(eagain()), r))
+                Err(r) => { let _ = restx.send((Err(eagain()), r)); },
             }
-        })
+        }))
     }
 
     /// Send a Pread request.
     ///
     /// On success, the returned usize indicates how much of `buf` was
     /// initialized. Otherwise on error, none of it will have been.
-    pub fn pread<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset, tok: T) {
+    pub fn pread<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset, tok: T) -> io::Result<()> {
         self.sendhelper(file, move |ctx, f| {
             ctx.pread(&f, buf, off, tok).map_err(|(buf, tok)| raw::IoOp::Pread(buf, tok))
         })
@@ -116,98 +160,159 @@ impl<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
     /// Send a Preadv request.
     ///
     /// On success, data is read into each element of `bufv` in turn.
-    pub fn preadv<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset, tok: T) {
+    pub fn preadv<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset, tok: T) -> io::Result<()> {
         self.sendhelper(file, move |ctx, f| {
             ctx.preadv(&f, bufv, off, tok).map_err(|(bufv, tok)| raw::IoOp::Preadv(bufv, tok))
         })
     }
 
     /// Send a Pwrite request.
-    pub fn pwrite<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset, tok: T) {
+    pub fn pwrite<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset, tok: T) -> io::Result<()> {
         self.sendhelper(file, move |ctx, f| {
             ctx.pwrite(&f, buf, off, tok).map_err(|(buf, tok)| raw::IoOp::Pwrite(buf, tok))
         })
     }
 
     /// Send a Pwritev request.
-    pub fn pwritev<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) {
+    pub fn pwritev<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) -> io::Result<()> {
         self.sendhelper(file, move |ctx, f| {
             ctx.pwritev(&f, bufv, off, tok).map_err(|(bufv, tok)| raw::IoOp::Pwritev(bufv, tok))
         })
     }
 
     /// Send a Fsync request.
-    pub fn fsync<F: AsRawFd>(&self, file: &F, tok: T) {
+    pub fn fsync<F: AsRawFd>(&self, file: &F, tok: T) -> io::Result<()> {
         self.sendhelper(file, move |ctx, f| {
             ctx.fsync(&f, tok).map_err(|tok| raw::IoOp::Fsync(tok))
         })
     }
 
     /// Send a Fdsync request.
-    pub fn fdsync<F: AsRawFd>(&self, file: &F, tok: T) {
+    pub fn fdsync<F: AsRawFd>(&self, file: &F, tok: T) -> io::Result<()> {
         self.sendhelper(file, move | ctx, f| {
             ctx.fdsync(&f, tok).map_err(|tok| raw::IoOp::Fdsync(tok))
         })
     }
 }
 
+impl<T : Send + 'static, Wb : WrBuf + Send + 'static, Rb : RdBuf + Send + 'static> Drop for Iocontext<T, Wb, Rb> {
+    fn drop(&mut self) {
+        // Close the channel before waking the worker, so it sees
+        // there's nothing more to come and finishes up.
+        self.optx = None;
+        let _ = self.wake.signal(1);
+    }
+}
+
 struct ChanWorker<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> {
     ctx: raw::Iocontext<T, Wb, Rb>,
 
     lowwater: usize,
+    flush: bool,                // submit everything batched, when there's room
+}
+
+// How long to wait before retrying a stalled submit, in ms.
+const BACKOFF: ::libc::c_int = 1;
+
+// Wait for any of `fds` to become readable, for up to `timeout` ms
+// (forever if -1).
+fn wait(fds: &[::libc::c_int], timeout: ::libc::c_int) -> io::Result<()> {
+    let mut pfds : Vec<_> = fds.iter().map(|&fd| ::libc::pollfd { fd, events: ::libc::POLLIN, revents: 0 }).collect();
+
+    loop {
+        if unsafe { ::libc::poll(pfds.as_mut_ptr(), pfds.len() as ::libc::nfds_t, timeout) } >= 0 {
+            return Ok(())
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(::libc::EINTR) {
+            return Err(e)
+        }
+    }
 }
 
 impl<T : Send, Wb : WrBuf + Send, Rb : RdBuf + Send> ChanWorker<T, Wb, Rb> {
-    fn proc_results(&mut self, restx: &Sender<IoRes<T, Wb, Rb>>) {
+    // Send on all the results which are ready, without blocking.
+    fn proc_results(&mut self, restx: &Sender<IoRes<T, Wb, Rb>>) -> io::Result<()> {
         if self.ctx.pending() == 0 {
-            return
+            return Ok(())
         }
 
+        // Drain first, so anything completing meanwhile signals again
+        self.ctx.eventfd().and_then(|evfd| evfd.drain())?;
+
         let max = self.ctx.maxops();
-        match self.ctx.results(1, max, None) {
-            Err(e) => panic!("get results failed {:?}", e),
-            Ok(v) =>
-                for s in v.into_iter().map(|(op, res)| (res, op)) {
-                    restx.send(s)
-                },
+        loop {
+            // The receiver may have gone, but the results still have
+            // to be collected.
+            match self.ctx.results_with(0, max, Some(Instant::now()), |op, res| { let _ = restx.send((res, op)); }) {
+                Err(e) => return Err(e),
+                Ok(0) => return Ok(()),
+                Ok(_) => (),
+            }
         }
     }
 
-    fn submit(&mut self) {
+    fn submit(&mut self) -> io::Result<()> {
         match self.ctx.submit() {
-            Err(e) => panic!("submit failed {:?}", e),
-            Ok(_) => (),
+            // No room in the kernel; stay batched until there is
+            Err(ref e) if e.raw_os_error() == Some(::libc::EAGAIN) => Ok(()),
+            Err(e) => Err(e),
+            Ok(_) => Ok(()),
         }
     }
 
     fn worker(&mut self,
-              oprx: Receiver<FnBox(&mut raw::Iocontext<T, Wb, Rb>, &Sender<IoRes<T, Wb, Rb>>)>,
-              restx: Sender<IoRes<T, Wb, Rb>>,
-              evfd: Receiver<u64>) {
+              oprx: Receiver<Callback<T, Wb, Rb>>,
+              restx: &Sender<IoRes<T, Wb, Rb>>,
+              wake: Arc<EventFd>) -> io::Result<()> {
+        let evfd = match self.ctx.eventfd() {
+            Err(e) => return Err(e),
+            Ok(evfd) => evfd.as_raw_fd(),
+        };
         let mut closed = false;
 
         while !closed || self.ctx.pending() != 0 {
-            if self.ctx.batched() > self.lowwater {
-                self.submit()
+            // Take new requests while there's room for them
+            if !closed && !self.ctx.full() {
+                wake.drain()?;
+
+                while !self.ctx.full() {
+                    match oprx.try_recv() {
+                        Ok(op) => op(self, restx),
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => { closed = true; break },
+                    }
+                }
             }
 
-            if closed || self.ctx.full() {
-                // Don't bother with new requests (we're either
-                // full-up or the input's closed), so just finish
-                // things off.
-                let _ = evfd.recv();
-                self.proc_results(&restx)
-            } else {
-                // full bidirectional
-                select!(
-                    op = oprx.recv_opt() => match op {
-                        Err(_) => { closed = true; self.submit() },
-                        Ok(op) => op(&mut self.ctx, &restx),
-                    },
-                    _ = evfd.recv() => self.proc_results(&restx)
-                );
+            let submitting = closed || self.flush || self.ctx.batched() > self.lowwater;
+            if submitting {
+                self.submit()?;
+                self.flush = self.ctx.batched() > 0;
+            }
+
+            self.proc_results(restx)?;
+
+            if closed && self.ctx.pending() == 0 {
+                break
             }
+
+            // If a submit stalled with nothing in flight, nothing will
+            // signal the eventfd, so back off and try again.
+            let stalled = submitting && self.ctx.submitted() == 0 && self.ctx.batched() > 0;
+            let timeout = if stalled { BACKOFF } else { -1 };
+
+            // If we're full-up or the input's closed, just wait for
+            // things to finish; otherwise wait for either.
+            let res = if closed || self.ctx.full() {
+                wait(&[evfd], timeout)
+            } else {
+                wait(&[evfd, wake.as_raw_fd()], timeout)
+            };
+            res?;
         }
+
+        Ok(())
     }
 }
 
@@ -217,6 +322,7 @@ mod test {
 
     use self::tempdir::TempDir;
     use std::fs::{File,OpenOptions};
+    use raw::IoOp;
     use super::Iocontext;
 
     fn tmpfile(name: &str) -> File {
@@ -240,16 +346,60 @@ mod test {
         };
         let file = tmpfile("chan");
 
-        let wbuf = Vec::from_fn(40, |_| 'x' as u8);
-        let rbuf = Vec::from_fn(100, |_| 0 as u8);
+        let wbuf = vec![b'x'; 40];
+        let rbuf = vec![0u8; 100];
         let res = io.resrx();
 
-        io.pread(&file, rbuf, 0, ());
-        io.pwrite(&file, wbuf, 0, ());
-        io.flush();
+        assert!(io.pwrite(&file, wbuf, 0, 0).is_ok());
+        assert!(io.fdsync(&file, 1).is_ok());
+        assert!(io.flush().is_ok());
+
+        let mut toks : Vec<_> = res.iter().take(2).map(|(res, op)| match op {
+            IoOp::Pwrite(_, tok) => { assert_eq!(res.ok(), Some(40)); tok },
+            IoOp::Fdsync(tok) => { assert!(res.is_ok()); tok },
+            op => panic!("unexpected {:?}", op),
+        }).collect();
+        toks.sort();
+        assert_eq!(toks, vec![0, 1]);
+
+        assert!(io.pread(&file, rbuf, 0, 2).is_ok());
+        assert!(io.flush().is_ok());
+        match res.recv().unwrap() {
+            (Ok(40), IoOp::Pread(buf, 2)) => assert_eq!(&buf[..40], &vec![b'x'; 40][..]),
+            (res, op) => panic!("unexpected {:?} {:?}", res, op),
+        }
+    }
+
+    #[test]
+    fn bad_args() {
+        for &(lowwater, max) in &[(0, 10), (10, 10), (11, 10)] {
+            match Iocontext::<u64, Vec<u8>, Vec<u8>>::new(lowwater, max) {
+                Err(e) => assert_eq!(e.raw_os_error(), Some(::libc::EINVAL)),
+                Ok(_) => panic!("accepted {} {}", lowwater, max),
+            }
+        }
+    }
 
-        for (res, op) in res.iter().take(2) {
-            println!("res {:?} op {:?}", res, op);
+    #[test]
+    fn backpressure() {
+        let io : Iocontext<u64, Vec<u8>, Vec<u8>> = Iocontext::new(2, 4).unwrap();
+        let file = tmpfile("backpressure");
+
+        // More than can be outstanding at once; the sends block until
+        // there's room, and the low water mark submits them.
+        for i in 0..20 {
+            assert!(io.pwrite(&file, vec![i as u8; 10], i * 10, i).is_ok());
         }
+        assert!(io.flush().is_ok());
+
+        let mut toks : Vec<_> = io.resrx().iter().take(20).map(|(res, op)| {
+            assert_eq!(res.ok(), Some(10));
+            match op {
+                IoOp::Pwrite(_, tok) => tok,
+                op => panic!("unexpected {:?}", op),
+            }
+        }).collect();
+        toks.sort();
+        assert_eq!(toks, (0..20).collect::<Vec<_>>());
     }
 }
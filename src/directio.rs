@@ -2,9 +2,11 @@
 extern crate std;
 extern crate libc;
 
-use libc::{c_void};
+use libc::{c_void, off_t, size_t};
 
+use std::ffi::CString;
 use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::io;
 use directio::Mode::*;
@@ -18,8 +20,6 @@ pub struct DirectFile {
     alignment: usize,
 }
 
-const O_DIRECT: i32 = 0x4000;   // Linux
-
 #[inline]
 fn retry<F: Fn() -> isize>(f: F) -> isize {
     loop {
@@ -45,7 +45,7 @@ pub enum FileAccess {
 impl DirectFile {
     // XXX auto-query directio alignment
     pub fn open<P: AsRef<Path>>(path: P, mode: Mode, fa: FileAccess, alignment: usize) -> io::Result<DirectFile> {
-        let flags = O_DIRECT | match mode {
+        let flags = libc::O_DIRECT | match mode {
             Open => 0,
             Append => libc::O_APPEND,
             Truncate => libc::O_TRUNC,
@@ -59,17 +59,20 @@ impl DirectFile {
                           libc::S_IRUSR | libc::S_IWUSR),
         };
 
-        let path = path.as_ref().as_os_str().to_bytes().unwrap();
-        match retry(|| unsafe { libc::open(path.as_ptr() as *const i8, flags, mode) as isize }) {
+        let path = match CString::new(path.as_ref().as_os_str().as_bytes()) {
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+            Ok(p) => p,
+        };
+        match retry(|| unsafe { libc::open(path.as_ptr(), flags, mode as ::libc::c_uint) as isize }) {
             -1 => Err(io::Error::last_os_error()),
-            fd => Ok(DirectFile { fd: FD(fd as i32), alignment: alignment }),
+            fd => Ok(DirectFile { fd: FD(fd as i32), alignment }),
         }
     }
 
     pub fn alignment(&self) -> usize { self.alignment }
 
     pub fn pread(&self, buf: &mut AlignedBuf, off: u64) -> io::Result<usize> {
-        let r = unsafe { ::libc::pread(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len() as u64, off as i64) };
+        let r = unsafe { ::libc::pread(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut c_void, buf.len() as size_t, off as off_t) };
 
         if r < 0 {
             Err(io::Error::last_os_error())
@@ -82,8 +85,8 @@ impl DirectFile {
         let r = unsafe {
             ::libc::pwrite(self.fd.as_raw_fd(),
                            buf.as_ptr() as *const c_void,
-                           buf.len() as u64,
-                           off as i64) };
+                           buf.len() as size_t,
+                           off as off_t) };
 
         if r < 0 {
             Err(io::Error::last_os_error())
@@ -109,7 +112,7 @@ mod test {
     use self::tempdir::TempDir;
     
     fn tmpfile(name: &str) -> DirectFile {
-        let tmp = TempDir::new_in(&Path::new("."), "test").unwrap();
+        let tmp = TempDir::new_in(Path::new("."), "test").unwrap();
         let mut path = tmp.into_path();
 
         path.push(name);
@@ -119,7 +122,7 @@ mod test {
     #[test]
     fn simple() {
         let file = tmpfile("direct");
-        let data = match AlignedBuf::from_slice(&['x' as u8; 4096][..], 4096) {
+        let data = match AlignedBuf::from_slice(&[b'x'; 4096][..], 4096) {
             None => panic!("buf alloc"),
             Some(b) => b
         };
@@ -0,0 +1,100 @@
+//! Completion notification eventfd.
+//!
+//! A context with an eventfd has the kernel signal it as each
+//! operation completes, so the context can be waited for with `poll`,
+//! `epoll` or an event loop along with other file descriptors. See
+//! `raw::Iocontext::eventfd`.
+extern crate std;
+
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use libc::c_void;
+
+/// Non-blocking eventfd.
+#[derive(Debug)]
+pub struct EventFd {
+    fd: RawFd,
+}
+
+impl EventFd {
+    /// Create a new eventfd, with a count of zero.
+    pub fn new() -> io::Result<EventFd> {
+        let fd = unsafe { ::libc::eventfd(0, ::libc::EFD_NONBLOCK | ::libc::EFD_CLOEXEC) };
+
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(EventFd { fd })
+        }
+    }
+
+    // Take a duplicate of the eventfd `fd`, for something which has
+    // to signal it for longer than its owner might keep it open.
+    pub(crate) fn dup(fd: RawFd) -> io::Result<EventFd> {
+        let fd = unsafe { ::libc::fcntl(fd, ::libc::F_DUPFD_CLOEXEC, 0) };
+
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(EventFd { fd })
+        }
+    }
+
+    /// Reset the count to zero without blocking, returning what it
+    /// was. The eventfd is readable whenever the count is non-zero,
+    /// so this should be done before reaping the results it signalled.
+    pub fn drain(&self) -> io::Result<u64> {
+        let mut count = 0u64;
+
+        loop {
+            let r = unsafe { ::libc::read(self.fd, &mut count as *mut u64 as *mut c_void, 8) };
+
+            if r == 8 {
+                return Ok(count)
+            }
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(::libc::EAGAIN) => return Ok(0),
+                Some(::libc::EINTR) => continue,
+                _ => return Err(e),
+            }
+        }
+    }
+
+    /// Add `n` to the count, making the eventfd readable.
+    pub fn signal(&self, n: u64) -> io::Result<()> {
+        let r = unsafe { ::libc::write(self.fd, &n as *const u64 as *const c_void, 8) };
+
+        if r == 8 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd { self.fd }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { ::libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EventFd;
+
+    #[test]
+    fn eventfd() {
+        let evfd = EventFd::new().unwrap();
+
+        assert_eq!(evfd.drain().ok(), Some(0));
+        assert!(evfd.signal(2).is_ok());
+        assert!(evfd.signal(3).is_ok());
+        assert_eq!(evfd.drain().ok(), Some(5));
+        assert_eq!(evfd.drain().ok(), Some(0));
+    }
+}
@@ -1,180 +1,468 @@
 //! Put AIO results into a Future
 //!
-//! This module represents pending AIO as a Future of the IO result.
+//! This module represents pending AIO as a `std::future::Future` of
+//! the IO result and the resources the operation used, so it can be
+//! awaited from any executor. The futures are woken by a reaper, which
+//! reaps completions when the context's eventfd is signalled.
+//! `Iocontext::new` runs the reaper on a thread of its own; with
+//! `Iocontext::manual` the caller runs it by calling `reap` whenever
+//! the context's file descriptor is readable, such as from an event
+//! loop.
+//!
+//! Operations are batched until one of their futures is polled, or
+//! `submit` is called.
 extern crate std;
 
-use std::comm;
-use std::sync::Future;
 use std::io;
-use std::os::unix::AsRawFd;
+use std::thread;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use buf::{RdBuf, WrBuf};
-use raw;
-use raw::IoOp;
-use super::eagain;
+use raw::{self, IoOp, Iohandle, SubmitPolicy};
+use super::Offset;
+
+fn eagain() -> io::Error {
+    io::Error::from_raw_os_error(::libc::EAGAIN)
+}
+
+// Result of an operation, and the task waiting for it.
+struct Slot<R> {
+    res: Option<(io::Result<usize>, R)>,
+    waker: Option<Waker>,
+}
 
-enum IoFut<Wb: WrBuf, Rb: RdBuf> {
-    Pread(SyncSender<(io::Result<uint>, Rb)>),
-    Preadv(SyncSender<(io::Result<uint>, Vec<Rb>)>),
-    Pwrite(SyncSender<(io::Result<uint>, Wb)>),
-    Pwritev(SyncSender<(io::Result<uint>, Vec<Wb>)>),
-    Fsync(SyncSender<io::Result<()>>),
+type SlotRef<R> = Arc<Mutex<Slot<R>>>;
+
+enum Completion<Wb, Rb> {
+    Pread(SlotRef<Rb>),
+    Preadv(SlotRef<Vec<Rb>>),
+    Pwrite(SlotRef<Wb>),
+    Pwritev(SlotRef<Vec<Wb>>),
+    Nobuf(SlotRef<()>),         // fsync, fdsync
 }
 
-type RawIoctx<Wb, Rb> = raw::Iocontext<IoFut<Wb, Rb>, Wb, Rb>;
+type RawIoctx<Wb, Rb> = raw::Iocontext<Completion<Wb, Rb>, Wb, Rb>;
+
+fn complete<R>(slot: SlotRef<R>, res: io::Result<usize>, r: R, wakers: &mut Vec<Waker>) {
+    let mut slot = slot.lock().unwrap();
 
-pub struct Iocontext<Wb: WrBuf + Send, Rb: RdBuf + Send> {
-    ctx: RawIoctx<Wb, Rb>,
+    slot.res = Some((res, r));
+    if let Some(w) = slot.waker.take() {
+        wakers.push(w)
+    }
 }
 
-impl<Wb: WrBuf + Send, Rb: RdBuf + Send> Iocontext<Wb, Rb> {
-    /// Construct a new Iocontext.
-    pub fn new(max: uint) -> io::Result<Iocontext<Wb, Rb>> {
-        Ok(Iocontext { ctx: try!(raw::Iocontext::new(max)) })
+// Store a result in its future's slot, collecting the waker to be
+// woken once the context is unlocked.
+fn dispatch<Wb: WrBuf, Rb: RdBuf>(op: IoOp<Completion<Wb, Rb>, Wb, Rb>, res: io::Result<usize>, wakers: &mut Vec<Waker>) {
+    match op {
+        IoOp::Pread(buf, Completion::Pread(s)) => complete(s, res, buf, wakers),
+        IoOp::Preadv(bufv, Completion::Preadv(s)) => complete(s, res, bufv, wakers),
+        IoOp::Pwrite(buf, Completion::Pwrite(s)) => complete(s, res, buf, wakers),
+        IoOp::Pwritev(bufv, Completion::Pwritev(s)) => complete(s, res, bufv, wakers),
+        IoOp::Fsync(Completion::Nobuf(s)) |
+        IoOp::Fdsync(Completion::Nobuf(s)) => complete(s, res, (), wakers),
+        IoOp::Noop => (),
+        _ => panic!("mismatched completion"),
     }
+}
 
-    fn results(&mut self) {
-        let max = self.ctx.maxops();
-        match self.ctx.results(1, max, None) {
-            Err(e) => panic!("get results failed {}", e),
-            Ok(res) =>
-                for (op, ores) in res.into_iter() {
-                    match op {
-                        IoOp::Noop => (),
+// State shared between the context, its reaper and its futures.
+struct Shared<Wb: WrBuf + Send, Rb: RdBuf + Send> {
+    ctx: Mutex<Option<RawIoctx<Wb, Rb>>>, // None once shut down
+    evfd: RawFd,
+    stop: AtomicBool,                     // reaper thread should exit
+}
 
-                        IoOp::Pread(buf, IoFut::Pread(tx)) => tx.send((ores, buf)),
-                        IoOp::Pread(_, _) => panic!("badness Pread"),
+impl<Wb: WrBuf + Send, Rb: RdBuf + Send> Shared<Wb, Rb> {
+    fn reap(&self) -> io::Result<usize> {
+        let mut wakers = Vec::new();
+        let mut n = 0;
+
+        {
+            let mut guard = self.ctx.lock().unwrap();
+            let ctx = match guard.as_mut() {
+                None => return Ok(0),
+                Some(ctx) => ctx,
+            };
+
+            // Drain first, so anything which completes while we're
+            // reaping signals again.
+            ctx.eventfd().and_then(|evfd| evfd.drain())?;
+
+            let max = ctx.maxops();
+            loop {
+                match ctx.results_with(0, max, Some(Instant::now()), |op, res| dispatch(op, res, &mut wakers)) {
+                    Err(e) => if n == 0 { return Err(e) } else { break },
+                    Ok(0) => break,
+                    Ok(r) => n += r,
+                }
+            }
 
-                        IoOp::Preadv(buf, IoFut::Preadv(tx)) => tx.send((ores, buf)),
-                        IoOp::Preadv(_, _) => panic!("badness Preadv"),
+            // Retry anything the kernel had no room for
+            if ctx.batched() > 0 {
+                let _ = ctx.submit();
+            }
+        }
 
-                        IoOp::Pwrite(buf, IoFut::Pwrite(tx)) => tx.send((ores, buf)),
-                        IoOp::Pwrite(_, _) => panic!("badness Pwrite"),
+        for w in wakers {
+            w.wake()
+        }
+        Ok(n)
+    }
+}
 
-                        IoOp::Pwritev(buf, IoFut::Pwritev(tx)) => tx.send((ores, buf)),
-                        IoOp::Pwritev(_, _) => panic!("badness Pwritev"),
+// Lets a future submit its operation when it's polled, without
+// knowing the context's buffer types.
+trait Flush: Send + Sync {
+    fn flush(&self);
+}
 
-                        IoOp::Fsync(IoFut::Fsync(tx)) => tx.send(ores.map(|_| ())),
-                        IoOp::Fdsync(IoFut::Fsync(tx)) => tx.send(ores.map(|_| ())),
-                        IoOp::Fsync(_) | IoOp::Fdsync(_) => panic!("badness fsync"),
-                    }
-                }
+impl<Wb: WrBuf + Send, Rb: RdBuf + Send> Flush for Shared<Wb, Rb> {
+    fn flush(&self) {
+        if let Some(ref mut ctx) = *self.ctx.lock().unwrap() {
+            if ctx.batched() > 0 {
+                let _ = ctx.submit();
+            }
         }
     }
+}
 
-    /// Submit all pending IO operations.
-    pub fn flush(&mut self) -> io::Result<()> {
-        match self.ctx.submit() {
-            Err(e) => return Err(e),
-            Ok(_) => (),
-        };
+/// Future result of an operation, along with the resources it used.
+pub struct IoFuture<R> {
+    slot: SlotRef<R>,
+    ctx: Option<Arc<dyn Flush>>,
+}
+
+impl<R> Future for IoFuture<R> {
+    type Output = (io::Result<usize>, R);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        {
+            let mut slot = self.slot.lock().unwrap();
 
-        while self.ctx.submitted() > 0 {
-            self.results();
+            if let Some(res) = slot.res.take() {
+                return Poll::Ready(res)
+            }
+            slot.waker = Some(cx.waker().clone());
         }
 
-        Ok(())
+        // Make sure the operation isn't sitting in a batch
+        if let Some(ref ctx) = self.ctx {
+            ctx.flush()
+        }
+        Poll::Pending
     }
+}
 
-    /// Submit a pread operation.
-    pub fn pread<F: AsRawFd>(&mut self, file: &F, buf: Rb, off: u64) -> Future<(io::Result<uint>, Rb)> {
-        let (tx, rx) = comm::sync_channel(1);
+/// Future result of an fsync or fdatasync.
+pub struct SyncFuture(IoFuture<()>);
 
-        match self.ctx.pread(file, buf, off, IoFut::Pread(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err((buf, _)) => Future::from_value((Err(eagain()), buf)),
+impl Future for SyncFuture {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match Pin::new(&mut self.0).poll(cx) {
+            Poll::Ready((res, ())) => Poll::Ready(res.map(|_| ())),
+            Poll::Pending => Poll::Pending,
         }
     }
+}
 
-    /// Submit a preadv operation.
-    pub fn preadv<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Rb>, off: u64) -> Future<(io::Result<uint>, Vec<Rb>)> {
-        let (tx, rx) = comm::sync_channel(1);
+// Wait for `fd` to become readable.
+fn wait(fd: RawFd) {
+    let mut pfd = ::libc::pollfd { fd, events: ::libc::POLLIN, revents: 0 };
 
-        match self.ctx.preadv(file, bufv, off, IoFut::Preadv(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err((bufv, _)) => Future::from_value((Err(eagain()), bufv)),
-        }
+    unsafe { ::libc::poll(&mut pfd, 1, -1) };
+}
+
+/// Future-based AIO context.
+///
+/// The context can be shared between tasks and threads; all the
+/// operations take `&self`. Dropping it cancels any operations which
+/// are still outstanding, and waits for those which can't be
+/// cancelled, so every future resolves.
+pub struct Iocontext<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> {
+    shared: Arc<Shared<Wb, Rb>>,
+    reaper: Option<thread::JoinHandle<()>>,
+}
+
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> Iocontext<Wb, Rb> {
+    fn setup(maxops: usize) -> io::Result<Arc<Shared<Wb, Rb>>> {
+        let mut ctx = raw::Iocontext::new(maxops)?;
+        let evfd = match ctx.eventfd() {
+            Err(e) => return Err(e),
+            Ok(evfd) => evfd.as_raw_fd(),
+        };
+
+        Ok(Arc::new(Shared { ctx: Mutex::new(Some(ctx)), evfd, stop: AtomicBool::new(false) }))
     }
 
-    /// Submit a pwrite operation.
-    pub fn pwrite<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: u64) -> Future<(io::Result<uint>, Wb)> {
-        let (tx, rx) = comm::sync_channel(1);
+    /// Construct a new Iocontext, with up to `maxops` outstanding
+    /// operations, and a thread to reap them.
+    pub fn new(maxops: usize) -> io::Result<Iocontext<Wb, Rb>> {
+        let shared = Iocontext::setup(maxops)?;
+        let s = shared.clone();
+        let reaper = thread::spawn(move || {
+            while !s.stop.load(Ordering::Acquire) {
+                let _ = s.reap();
+                // Check again before waiting: reaping drains the
+                // eventfd, which may have eaten the signal to stop
+                if s.stop.load(Ordering::Acquire) {
+                    break
+                }
+                wait(s.evfd);
+            }
+        });
+
+        Ok(Iocontext { shared, reaper: Some(reaper) })
+    }
 
-        match self.ctx.pwrite(file, buf, off, IoFut::Pwrite(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err((buf, _)) => Future::from_value((Err(eagain()), buf)),
+    /// Construct a new Iocontext without a reaper thread. `reap`
+    /// must be called whenever the context's file descriptor is
+    /// readable.
+    pub fn manual(maxops: usize) -> io::Result<Iocontext<Wb, Rb>> {
+        match Iocontext::setup(maxops) {
+            Err(e) => Err(e),
+            Ok(shared) => Ok(Iocontext { shared, reaper: None }),
         }
     }
 
-    /// Submit a pwritev operation.
-    pub fn pwritev<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: u64) -> Future<(io::Result<uint>, Vec<Wb>)> {
-        let (tx, rx) = comm::sync_channel(1);
+    // Shut the raw context down, resolving every outstanding future.
+    // Only the reaper and the futures may use the context afterwards.
+    pub(crate) fn close(&self) {
+        let ctx = self.shared.ctx.lock().unwrap().take();
 
-        match self.ctx.pwritev(file, bufv, off, IoFut::Pwritev(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err((bufv, _)) => Future::from_value((Err(eagain()), bufv)),
+        if let Some(ctx) = ctx {
+            let mut wakers = Vec::new();
+
+            for (op, res) in ctx.shutdown(None) {
+                dispatch(op, res, &mut wakers)
+            }
+            for w in wakers {
+                w.wake()
+            }
         }
     }
 
-    /// Submit an fsync.
-    pub fn fsync<F: AsRawFd>(&mut self, file: &F) -> Future<io::Result<()>> {
-        let (tx, rx) = comm::sync_channel(1);
+    /// Reap completed operations without blocking, waking their
+    /// futures. Returns the number reaped.
+    pub fn reap(&self) -> io::Result<usize> { self.shared.reap() }
 
-        match self.ctx.fsync(file, IoFut::Fsync(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err(_) => Future::from_value(Err(eagain())),
+    /// Submit all batched operations. See `raw::Iocontext::submit`.
+    pub fn submit(&self) -> io::Result<usize> {
+        self.with(|ctx| ctx.submit())
+    }
+
+    /// Set when operations are submitted automatically. See
+    /// `raw::Iocontext::set_submit_policy`. Operations are always
+    /// submitted when their futures are polled.
+    pub fn set_submit_policy(&self, policy: SubmitPolicy) {
+        self.with(|ctx| ctx.set_submit_policy(policy))
+    }
+
+    /// Number of operations whose futures haven't been resolved.
+    pub fn pending(&self) -> usize { self.with(|ctx| ctx.pending()) }
+
+    fn with<F, X>(&self, f: F) -> X
+        where F: FnOnce(&mut RawIoctx<Wb, Rb>) -> X
+    {
+        // Only taken by close
+        f(self.shared.ctx.lock().unwrap().as_mut().unwrap())
+    }
+
+    // Queue an operation; if the context is full, the future is
+    // resolved with EAGAIN straight away.
+    fn queue<R, Q>(&self, r: R, mk: fn(SlotRef<R>) -> Completion<Wb, Rb>, q: Q) -> IoFuture<R>
+        where Q: FnOnce(&mut RawIoctx<Wb, Rb>, R, Completion<Wb, Rb>) -> Result<Iohandle, (R, Completion<Wb, Rb>)>
+    {
+        let slot = Arc::new(Mutex::new(Slot { res: None, waker: None }));
+
+        match self.with(|ctx| q(ctx, r, mk(slot.clone()))) {
+            Ok(_) => IoFuture { slot, ctx: Some(self.shared.clone()) },
+            Err((r, _)) => {
+                slot.lock().unwrap().res = Some((Err(eagain()), r));
+                IoFuture { slot, ctx: None }
+            },
         }
     }
 
-    /// Submit an fdatasync.
-    pub fn fdsync<F: AsRawFd>(&mut self, file: &F) -> Future<io::Result<()>> {
-        let (tx, rx) = comm::sync_channel(1);
+    /// Queue a pread.
+    pub fn pread<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset) -> IoFuture<Rb> {
+        self.queue(buf, Completion::Pread, |ctx, buf, c| ctx.pread(file, buf, off, c))
+    }
+
+    /// Queue a preadv.
+    pub fn preadv<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset) -> IoFuture<Vec<Rb>> {
+        self.queue(bufv, Completion::Preadv, |ctx, bufv, c| ctx.preadv(file, bufv, off, c))
+    }
+
+    /// Queue a pwrite.
+    pub fn pwrite<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset) -> IoFuture<Wb> {
+        self.queue(buf, Completion::Pwrite, |ctx, buf, c| ctx.pwrite(file, buf, off, c))
+    }
+
+    /// Queue a pwritev.
+    pub fn pwritev<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset) -> IoFuture<Vec<Wb>> {
+        self.queue(bufv, Completion::Pwritev, |ctx, bufv, c| ctx.pwritev(file, bufv, off, c))
+    }
+
+    /// Queue an fsync.
+    pub fn fsync<F: AsRawFd>(&self, file: &F) -> SyncFuture {
+        SyncFuture(self.queue((), Completion::Nobuf, |ctx, (), c| ctx.fsync(file, c).map_err(|c| ((), c))))
+    }
+
+    /// Queue an fdatasync.
+    pub fn fdsync<F: AsRawFd>(&self, file: &F) -> SyncFuture {
+        SyncFuture(self.queue((), Completion::Nobuf, |ctx, (), c| ctx.fdsync(file, c).map_err(|c| ((), c))))
+    }
+}
+
+/// The context's eventfd, which is readable when there are
+/// completions to reap.
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> AsRawFd for Iocontext<Wb, Rb> {
+    fn as_raw_fd(&self) -> RawFd { self.shared.evfd }
+}
 
-        match self.ctx.fdsync(file, IoFut::Fsync(tx)) {
-            Ok(()) => Future::from_receiver(rx),
-            Err(_) => Future::from_value(Err(eagain())),
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> Drop for Iocontext<Wb, Rb> {
+    fn drop(&mut self) {
+        if let Some(reaper) = self.reaper.take() {
+            self.shared.stop.store(true, Ordering::Release);
+            self.with(|ctx| { let _ = ctx.eventfd().and_then(|evfd| evfd.signal(1)); });
+            let _ = reaper.join();
         }
+
+        self.close()
     }
 }
 
 #[cfg(test)]
 mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+    use std::thread;
+    use std::os::unix::io::AsRawFd;
     use super::Iocontext;
 
-    use std::io::{TempDir, File, Truncate, ReadWrite};
-
     fn tmpfile(name: &str) -> File {
         let tmp = TempDir::new("test").unwrap();
-        let mut path = tmp.path().clone();
+        let mut path = tmp.into_path();
 
         path.push(name);
-        File::open_mode(&path, Truncate, ReadWrite).unwrap()
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) { self.0.unpark() }
+    }
+
+    fn block_on<F: Future + Unpin>(mut fut: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match Pin::new(&mut fut).poll(&mut cx) {
+                Poll::Ready(r) => return r,
+                Poll::Pending => thread::park(),
+            }
+        }
     }
 
     #[test]
     fn simple() {
-        let mut io = match Iocontext::new(10) {
-            Err(e) => panic!("new failed {}", e),
-            Ok(t) => t,
-        };
-        let file = tmpfile("chan");
+        let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+        let file = tmpfile("future");
+
+        let (res, wbuf) = block_on(io.pwrite(&file, vec![b'x'; 40], 0));
+        assert_eq!(res.ok(), Some(40));
+
+        let w = io.pwritev(&file, vec![vec![b'y'; 10], vec![b'z'; 10]], 40);
+        let s = io.fdsync(&file);
+        assert_eq!(block_on(w).0.ok(), Some(20));
+        assert!(block_on(s).is_ok());
+
+        let (res, rbuf) = block_on(io.pread(&file, vec![0; 100], 0));
+        assert_eq!(res.ok(), Some(60));
+        assert_eq!(&rbuf[..40], &wbuf[..]);
+        assert_eq!(&rbuf[40..60], &b"yyyyyyyyyyzzzzzzzzzz"[..]);
+        assert_eq!(io.pending(), 0);
+    }
 
-        let wbuf = Vec::from_fn(40, |_| 'x' as u8);
-        let rbuf = Vec::from_fn(100, |_| 0 as u8);
+    #[test]
+    fn manual() {
+        let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::manual(1).unwrap();
+        let file = tmpfile("manual");
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut w = io.pwrite(&file, vec![b'x'; 10], 0);
+
+        // Full, so this resolves straight away
+        let (res, buf) = block_on(io.pwrite(&file, vec![b'y'; 10], 0));
+        assert_eq!(res.err().and_then(|e| e.raw_os_error()), Some(::libc::EAGAIN));
+        assert_eq!(buf, vec![b'y'; 10]);
+
+        // Polling submits, but nothing is reaped until we ask
+        assert!(Pin::new(&mut w).poll(&mut cx).is_pending());
+        assert_eq!(io.pending(), 1);
+
+        let mut pfd = ::libc::pollfd { fd: io.as_raw_fd(), events: ::libc::POLLIN, revents: 0 };
+        assert_eq!(unsafe { ::libc::poll(&mut pfd, 1, 1000) }, 1);
+        assert_eq!(io.reap().ok(), Some(1));
+
+        match Pin::new(&mut w).poll(&mut cx) {
+            Poll::Ready((res, _)) => assert_eq!(res.ok(), Some(10)),
+            Poll::Pending => panic!("not ready"),
+        }
+    }
 
-        let w = io.pwrite(&file, wbuf, 0);
-        let r = io.pread(&file, rbuf, 0);
+    #[test]
+    fn drop_ctx() {
+        let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+        let file = tmpfile("drop");
 
-        assert!(io.flush().is_ok());
+        // Never polled, so never submitted
+        let w = io.pwrite(&file, vec![b'x'; 10], 0);
+        drop(io);
 
-        let wb2 = match w.into_inner() {
-            (Ok(sz), wb) => { assert_eq!(sz, 40); wb },
-            (Err(e), _) => panic!("write failed {}", e),
-        };
+        let (res, buf) = block_on(w);
+        assert_eq!(res.err().and_then(|e| e.raw_os_error()), Some(::libc::ECANCELED));
+        assert_eq!(buf.len(), 10);
+    }
 
-        match r.into_inner() {
-            (Ok(sz), rb) => { assert_eq!(sz, 40); assert_eq!(rb[0 .. 40], wb2.as_slice()) },
-            (Err(e), _) => panic!("read failed {}", e),
-        }        
+    #[test]
+    fn drop_inflight() {
+        let file = tmpfile("dropinflight");
+
+        // The reaper is woken by completions while it's being stopped
+        for _ in 0..50 {
+            let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+            let futs : Vec<_> = (0..5).map(|i| io.pwrite(&file, vec![b'x'; 10], i * 10)).collect();
+
+            assert!(io.submit().is_ok());
+            drop(io);
+            for f in futs {
+                let _ = block_on(f);
+            }
+        }
     }
 }
@@ -1,18 +1,26 @@
-#![feature(convert)]
-#![feature(heap_api)]
-
 extern crate libc;
 
 pub use buf::{RdBuf,WrBuf};
 use std::os::unix::io::{RawFd, AsRawFd};
 
-mod aioabi;
+pub mod aioabi;
 mod buf;
 mod pool;
 
 pub mod raw;
-//pub mod chan;
-//pub mod future;
+pub mod backend;
+#[cfg(feature = "uring")]
+pub mod uring;
+pub mod mock;
+pub mod threadpool;
+pub mod callback;
+pub mod stats;
+pub mod eventfd;
+pub mod shared;
+pub mod chan;
+pub mod future;
+#[cfg(feature = "tokio")]
+pub mod tokio;
 pub mod directio;
 pub mod aligned;
 
@@ -0,0 +1,650 @@
+//! Simulated AIO backend for testing.
+//!
+//! `Mock` performs operations against in-memory files rather than the
+//! kernel, and can inject failures, short transfers, delays and
+//! reordered completions. Random behaviour comes from a seeded
+//! generator, so a test run can be repeated exactly.
+//!
+//! Files are identified only by file descriptor; any descriptor which
+//! isn't negative refers to a file, which starts out empty. Nothing is
+//! done to the real file.
+//!
+//! ```ignore
+//! let mut io = raw::Iocontext::with_backend(10, Mock::new(seed));
+//! io.backend_mut().inject(Fault::Error(libc::EIO));
+//! ```
+extern crate std;
+
+use std::io;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::slice;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use aioabi as aio;
+use backend::Backend;
+use stats::nanos;
+
+/// A fault to inject into an operation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// No fault; the operation behaves normally.
+    None,
+    /// Submission of the operation fails with this errno.
+    Reject(i32),
+    /// The operation fails with this errno when it completes.
+    Error(i32),
+    /// The operation transfers at most this many bytes.
+    Short(usize),
+    /// The operation takes at least this long to complete.
+    Delay(Duration),
+}
+
+// Operation which has been submitted but not reaped.
+struct Pending {
+    iocb: *mut aio::Struct_iocb,
+    ready: Instant,             // when it can complete
+    fault: Fault,
+}
+
+// Eventfd to signal when a delayed operation completes.
+struct Signal {
+    iocb: u64,                  // address, as a key
+    when: Instant,
+    fd: RawFd,                  // duplicate, so closing the original is harmless
+}
+
+// Delayed signals, shared with the thread which makes them.
+struct Timer {
+    state: Mutex<(Vec<Signal>, bool)>, // signals, stopping
+    cond: Condvar,
+}
+
+/// In-memory simulated backend.
+pub struct Mock {
+    rng: u64,                   // xorshift state
+    files: HashMap<RawFd, Vec<u8>>,
+    pending: Vec<Pending>,      // in submission order
+    faults: VecDeque<Fault>,    // for the next operations submitted
+
+    error_rate: f64,
+    errno: i32,
+    short_rate: f64,
+    max_delay: Duration,
+    reorder: bool,
+    capacity: Option<usize>,
+
+    timer: Option<(Arc<Timer>, JoinHandle<()>)>, // started by the first delayed signal
+}
+
+// The iocbs are only touched while the owning context is borrowed.
+unsafe impl Send for Mock {}
+
+impl Mock {
+    /// Create a new mock backend, whose random choices are determined
+    /// by `seed`. By default it injects no faults.
+    pub fn new(seed: u64) -> Mock {
+        Mock {
+            rng: if seed == 0 { 0x9e3779b97f4a7c15 } else { seed },
+            files: HashMap::new(),
+            pending: Vec::new(),
+            faults: VecDeque::new(),
+            error_rate: 0.0,
+            errno: ::libc::EIO,
+            short_rate: 0.0,
+            max_delay: Duration::from_secs(0),
+            reorder: false,
+            capacity: None,
+            timer: None,
+        }
+    }
+
+    /// Queue a fault for the next operation submitted. Each queued
+    /// fault applies to one operation, in order; once they're used up,
+    /// the random rates apply again.
+    pub fn inject(&mut self, fault: Fault) { self.faults.push_back(fault) }
+
+    /// Fail a fraction `rate` of operations with `errno`.
+    pub fn set_error_rate(&mut self, rate: f64, errno: i32) {
+        self.error_rate = rate;
+        self.errno = errno;
+    }
+
+    /// Make a fraction `rate` of reads and writes transfer a random
+    /// amount less than requested.
+    pub fn set_short_rate(&mut self, rate: f64) { self.short_rate = rate }
+
+    /// Delay each operation's completion by a random amount up to
+    /// `delay`. Unless completions are reordered, operations complete
+    /// in the order their delays expire.
+    pub fn set_max_delay(&mut self, delay: Duration) { self.max_delay = delay }
+
+    /// Return completed operations in random order, rather than the
+    /// order they completed.
+    pub fn set_reorder(&mut self, reorder: bool) { self.reorder = reorder }
+
+    /// Limit the number of operations in flight; submissions beyond
+    /// it fail with `EAGAIN`. `None` means no limit.
+    pub fn set_capacity(&mut self, capacity: Option<usize>) { self.capacity = capacity }
+
+    /// Return the contents of the file for `fd`, if it's been used.
+    pub fn file(&self, fd: RawFd) -> Option<&[u8]> { self.files.get(&fd).map(|f| &f[..]) }
+
+    /// Set the contents of the file for `fd`.
+    pub fn set_file(&mut self, fd: RawFd, data: Vec<u8>) { self.files.insert(fd, data); }
+
+    /// Number of operations submitted but not yet reaped.
+    pub fn inflight(&self) -> usize { self.pending.len() }
+
+    // xorshift64*
+    fn next(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // Uniform in [0, 1)
+    fn uniform(&mut self) -> f64 { (self.next() >> 11) as f64 / (1u64 << 53) as f64 }
+
+    // Uniform in [0, n)
+    fn below(&mut self, n: u64) -> u64 { if n == 0 { 0 } else { self.next() % n } }
+
+    // Choose the fault for an operation.
+    fn fault(&mut self, iocb: &aio::Struct_iocb) -> Fault {
+        if let Some(f) = self.faults.pop_front() {
+            return f
+        }
+
+        if self.error_rate > 0.0 && self.uniform() < self.error_rate {
+            Fault::Error(self.errno)
+        } else if self.short_rate > 0.0 && iocb.aio_count > 0 && is_rw(iocb.aio_lio_opcode) &&
+            self.uniform() < self.short_rate {
+            let total = transfer_len(iocb);
+            Fault::Short(self.below(total as u64) as usize)
+        } else {
+            Fault::None
+        }
+    }
+
+    // Move up to `events.len()` operations which are ready by `now`
+    // into `events`, performing them.
+    fn collect(&mut self, events: &mut [aio::Struct_io_event], now: Instant) -> usize {
+        let mut n = 0;
+
+        while n < events.len() {
+            let ready : Vec<usize> = (0..self.pending.len()).filter(|&i| self.pending[i].ready <= now).collect();
+
+            if ready.is_empty() {
+                break
+            }
+
+            let i = if self.reorder {
+                let r = self.below(ready.len() as u64) as usize;
+                ready[r]
+            } else {
+                // Earliest ready, then earliest submitted
+                *ready.iter().min_by_key(|&&i| self.pending[i].ready).unwrap()
+            };
+            let p = self.pending.remove(i);
+
+            // The timer may not have got to it yet
+            self.unsignal(p.iocb, true);
+            events[n] = unsafe { self.perform(p.iocb, p.fault) };
+            n += 1;
+        }
+
+        n
+    }
+
+    // Signal the eventfd `fd` at `when`, when `iocb` completes, as the
+    // kernel would. Delayed signals all come from one timer thread.
+    fn signal_at(&mut self, iocb: *mut aio::Struct_iocb, fd: RawFd, when: Instant) -> io::Result<()> {
+        if when <= Instant::now() {
+            signal(fd);
+            return Ok(())
+        }
+
+        let fd = unsafe { ::libc::dup(fd) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let timer = match self.timer {
+            Some((ref timer, _)) => timer.clone(),
+            None => {
+                let timer = Arc::new(Timer { state: Mutex::new((Vec::new(), false)), cond: Condvar::new() });
+                let t = timer.clone();
+                let thr = match thread::Builder::new().name("mock timer".into()).spawn(move || t.run()) {
+                    Ok(thr) => thr,
+                    Err(e) => { unsafe { ::libc::close(fd) }; return Err(e) },
+                };
+                self.timer = Some((timer.clone(), thr));
+                timer
+            },
+        };
+
+        timer.state.lock().unwrap().0.push(Signal { iocb: iocb as u64, when, fd });
+        timer.cond.notify_one();
+        Ok(())
+    }
+
+    // Drop any pending signal for `iocb`, making it now if `fire`.
+    fn unsignal(&mut self, iocb: *mut aio::Struct_iocb, fire: bool) {
+        if let Some((ref timer, _)) = self.timer {
+            let mut state = timer.state.lock().unwrap();
+
+            if let Some(i) = state.0.iter().position(|s| s.iocb == iocb as u64) {
+                let s = state.0.swap_remove(i);
+                if fire {
+                    signal(s.fd)
+                }
+                unsafe { ::libc::close(s.fd) };
+            }
+        }
+    }
+
+    // Carry out an operation, returning its completion event.
+    unsafe fn perform(&mut self, iocb: *mut aio::Struct_iocb, fault: Fault) -> aio::Struct_io_event {
+        let res = match fault {
+            Fault::Error(errno) => -errno as i64,
+            Fault::Short(max) => self.execute(&*iocb, max),
+            _ => self.execute(&*iocb, usize::MAX),
+        };
+
+        aio::Struct_io_event { data: (*iocb).data, obj: iocb as u64, res, res2: 0 }
+    }
+
+    // Perform an operation, transferring at most `max` bytes. Returns
+    // the result or -errno.
+    unsafe fn execute(&mut self, iocb: &aio::Struct_iocb, max: usize) -> i64 {
+        let fd = iocb.aio_fildes as i32;
+        if fd < 0 {
+            return -::libc::EBADF as i64
+        }
+
+        let file = self.files.entry(fd).or_default();
+        let off = iocb.aio_offset as usize;
+
+        match iocb.aio_lio_opcode {
+            op if op == aio::Iocmd::IO_CMD_PREAD as u16 || op == aio::Iocmd::IO_CMD_PWRITE as u16 => {
+                let iov = [aio::Struct_iovec { iov_base: iocb.aio_buf as *mut u8,
+                                               iov_len: iocb.aio_count as ::libc::size_t }];
+                transfer(file, off, &iov, op == aio::Iocmd::IO_CMD_PWRITE as u16, max) as i64
+            },
+            op if op == aio::Iocmd::IO_CMD_PREADV as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16 => {
+                let iov = slice::from_raw_parts(iocb.aio_buf as *const aio::Struct_iovec, iocb.aio_count as usize);
+                transfer(file, off, iov, op == aio::Iocmd::IO_CMD_PWRITEV as u16, max) as i64
+            },
+            // Files are always ready
+            op if op == aio::Iocmd::IO_CMD_POLL as u16 => (iocb.aio_buf as u16) as i64,
+            _ => 0,             // fsync, fdsync
+        }
+    }
+}
+
+impl Drop for Mock {
+    fn drop(&mut self) {
+        if let Some((timer, thr)) = self.timer.take() {
+            timer.state.lock().unwrap().1 = true;
+            timer.cond.notify_one();
+            let _ = thr.join();
+
+            for s in timer.state.lock().unwrap().0.drain(..) {
+                unsafe { ::libc::close(s.fd) };
+            }
+        }
+    }
+}
+
+impl Timer {
+    // Make each signal when it's due, until stopped.
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.1 {
+            let now = Instant::now();
+
+            state.0.retain(|s| {
+                if s.when > now {
+                    return true
+                }
+                signal(s.fd);
+                unsafe { ::libc::close(s.fd) };
+                false
+            });
+
+            state = match state.0.iter().map(|s| s.when).min() {
+                None => self.cond.wait(state).unwrap(),
+                Some(when) => self.cond.wait_timeout(state, when - now).unwrap().0,
+            };
+        }
+    }
+}
+
+fn signal(fd: RawFd) {
+    let one = 1u64;
+
+    unsafe { ::libc::write(fd, &one as *const u64 as *const ::libc::c_void, 8) };
+}
+
+fn is_rw(op: u16) -> bool {
+    op == aio::Iocmd::IO_CMD_PREAD as u16 || op == aio::Iocmd::IO_CMD_PWRITE as u16 ||
+        op == aio::Iocmd::IO_CMD_PREADV as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16
+}
+
+fn supported(op: u16) -> bool {
+    is_rw(op) || op == aio::Iocmd::IO_CMD_FSYNC as u16 || op == aio::Iocmd::IO_CMD_FDSYNC as u16 ||
+        op == aio::Iocmd::IO_CMD_POLL as u16
+}
+
+// Total bytes a read or write asks for.
+fn transfer_len(iocb: &aio::Struct_iocb) -> usize {
+    let op = iocb.aio_lio_opcode;
+
+    if op == aio::Iocmd::IO_CMD_PREADV as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16 {
+        let iov = unsafe { slice::from_raw_parts(iocb.aio_buf as *const aio::Struct_iovec, iocb.aio_count as usize) };
+        iov.iter().map(|v| v.iov_len).sum()
+    } else {
+        iocb.aio_count as usize
+    }
+}
+
+// Copy between `file` at `off` and the buffers in `iov`, up to `max`
+// bytes. Reads stop at the end of the file; writes extend it.
+unsafe fn transfer(file: &mut Vec<u8>, mut off: usize, iov: &[aio::Struct_iovec], write: bool, mut max: usize) -> usize {
+    let mut done = 0;
+
+    for v in iov {
+        let len = min(v.iov_len, max);
+        let buf = slice::from_raw_parts_mut(v.iov_base, len);
+
+        let n = if write {
+            if file.len() < off + len {
+                file.resize(off + len, 0)
+            }
+            file[off..off + len].copy_from_slice(buf);
+            len
+        } else {
+            let n = min(len, file.len().saturating_sub(off));
+            buf[..n].copy_from_slice(&file[off..off + n]);
+            n
+        };
+
+        done += n;
+        off += n;
+        max -= n;
+        if n < v.iov_len {
+            break
+        }
+    }
+
+    done
+}
+
+impl Backend for Mock {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        let now = Instant::now();
+        let mut n = 0;
+
+        for &iocb in iocbs {
+            if self.capacity.is_some_and(|c| self.pending.len() >= c) {
+                break
+            }
+
+            let fault = self.fault(&*iocb);
+            let err = match fault {
+                Fault::Reject(errno) => Some(errno),
+                _ if !supported((*iocb).aio_lio_opcode) => Some(::libc::EINVAL),
+                _ => None,
+            };
+            if let Some(errno) = err {
+                if n == 0 {
+                    return Err(io::Error::from_raw_os_error(errno))
+                }
+                // Reject it next time
+                self.faults.push_front(fault);
+                break
+            }
+
+            let mut delay = Duration::from_nanos(self.below(nanos(self.max_delay) + 1));
+            if let Fault::Delay(d) = fault {
+                delay += d
+            }
+
+            if (*iocb).aio_flags & aio::IOCB_FLAG_RESFD != 0 {
+                if let Err(e) = self.signal_at(iocb, (*iocb).aio_resfd as RawFd, now + delay) {
+                    if n == 0 {
+                        return Err(e)
+                    }
+                    self.faults.push_front(fault);
+                    break
+                }
+            }
+
+            self.pending.push(Pending { iocb, ready: now + delay, fault });
+            n += 1;
+        }
+
+        if n == 0 && !iocbs.is_empty() {
+            Err(io::Error::from_raw_os_error(::libc::EAGAIN))
+        } else {
+            Ok(n)
+        }
+    }
+
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        match self.pending.iter().position(|p| p.iocb == iocb) {
+            None => Err(io::Error::from_raw_os_error(::libc::EINVAL)),
+            Some(i) => {
+                self.pending.remove(i);
+                self.unsignal(iocb, false);
+                Ok(())
+            },
+        }
+    }
+
+    // If fewer than `min` operations are in flight this waits for the
+    // deadline, or returns at once if there is none, rather than
+    // blocking forever.
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        let mut n = 0;
+
+        loop {
+            let now = Instant::now();
+
+            n += self.collect(&mut events[n..], now);
+            if n >= min || n == events.len() {
+                return Ok(n)
+            }
+
+            let next = self.pending.iter().map(|p| p.ready).min();
+            let wake = match (next, deadline) {
+                (Some(r), Some(d)) if d < r => d,
+                (Some(r), _) => r,
+                (None, Some(d)) => d,
+                (None, None) => return Ok(n),
+            };
+
+            if wake <= now {
+                if deadline.is_some_and(|d| d <= now) {
+                    return Ok(n)
+                }
+            } else {
+                thread::sleep(wake - now)
+            }
+        }
+    }
+
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        Some(self.collect(events, Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::time::{Duration, Instant};
+    use raw::{Iocontext, IoOp};
+    use super::super::FD;
+    use super::{Mock, Fault};
+
+    type Ctx = Iocontext<usize, Vec<u8>, Vec<u8>, Mock>;
+
+    #[allow(clippy::type_complexity)]
+    fn reap_all(io: &mut Ctx) -> Vec<(IoOp<usize, Vec<u8>, Vec<u8>>, ::std::io::Result<usize>)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut res = Vec::new();
+
+        while io.pending() > 0 {
+            assert!(io.results_into(io.pending(), 100, Some(deadline), &mut res).is_ok());
+            assert!(Instant::now() < deadline, "timed out");
+        }
+        res
+    }
+
+    #[test]
+    fn readwrite() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+        let file = FD(3);
+
+        io.backend_mut().set_file(3, b"hello".to_vec());
+        assert!(io.pwrite(&file, b" world".to_vec(), 5, 0).is_ok());
+        assert!(io.submit().is_ok());
+        assert_eq!(reap_all(&mut io)[0].1.as_ref().ok(), Some(&6));
+        assert_eq!(io.backend().file(3), Some(&b"hello world"[..]));
+
+        assert!(io.preadv(&file, vec![vec![0; 4], vec![0; 20]], 2, 1).is_ok());
+        assert!(io.submit().is_ok());
+        match reap_all(&mut io).pop().unwrap() {
+            (IoOp::Preadv(bufv, 1), Ok(9)) => {
+                assert_eq!(&bufv[0][..], b"llo ");
+                assert_eq!(&bufv[1][..5], b"world");
+            },
+            (op, res) => panic!("unexpected {:?} {:?}", op, res),
+        }
+    }
+
+    #[test]
+    fn faults() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+        let file = FD(3);
+
+        io.backend_mut().inject(Fault::Error(::libc::EIO));
+        io.backend_mut().inject(Fault::Short(3));
+        io.backend_mut().inject(Fault::None);
+        io.backend_mut().inject(Fault::Reject(::libc::EBADF));
+
+        for i in 0..4 {
+            assert!(io.pwrite(&file, vec![i as u8; 10], 10 * i as u64, i).is_ok());
+        }
+        assert_eq!(io.submit().ok(), Some(3));
+
+        let mut res : Vec<_> = reap_all(&mut io).into_iter().map(|(op, res)| match op {
+            IoOp::Pwrite(_, i) => (i, res.map_err(|e| e.raw_os_error().unwrap())),
+            op => panic!("unexpected {:?}", op),
+        }).collect();
+        res.sort();
+
+        assert_eq!(res, vec![(0, Err(::libc::EIO)), (1, Ok(3)), (2, Ok(10)), (3, Err(::libc::EBADF))]);
+    }
+
+    #[test]
+    fn delay() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+
+        io.backend_mut().inject(Fault::Delay(Duration::from_millis(50)));
+        assert!(io.fsync(&FD(3), 0).is_ok());
+        assert!(io.submit().is_ok());
+
+        assert_eq!(io.try_results(10).unwrap().len(), 0);
+        assert_eq!(io.results(1, 10, Some(Duration::from_millis(5))).unwrap().len(), 0);
+
+        let start = Instant::now();
+        assert_eq!(io.results(1, 10, Some(Duration::from_secs(1))).unwrap().len(), 1);
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    // Whether `fd` becomes readable within `ms` milliseconds.
+    fn readable(fd: RawFd, ms: i32) -> bool {
+        let mut pfd = ::libc::pollfd { fd, events: ::libc::POLLIN, revents: 0 };
+
+        unsafe { ::libc::poll(&mut pfd, 1, ms) == 1 }
+    }
+
+    #[test]
+    fn eventfd() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+        let fd = io.eventfd().unwrap().as_raw_fd();
+
+        io.backend_mut().inject(Fault::Delay(Duration::from_millis(50)));
+        assert!(io.fsync(&FD(3), 0).is_ok());
+        assert!(io.submit().is_ok());
+
+        // Not signalled until it completes, then without reaping
+        assert!(!readable(fd, 0));
+        assert!(readable(fd, 1000));
+        assert_eq!(io.try_results(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn cancel_eventfd() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+        let fd = io.eventfd().unwrap().as_raw_fd();
+
+        io.backend_mut().inject(Fault::Delay(Duration::from_millis(20)));
+        let h = io.fsync(&FD(3), 0).ok().unwrap();
+        assert!(io.submit().is_ok());
+        assert!(io.cancel(h).is_ok());
+
+        // Its signal went with it
+        assert!(!readable(fd, 100));
+    }
+
+    #[test]
+    fn capacity() {
+        let mut io : Ctx = Iocontext::with_backend(10, Mock::new(1));
+
+        io.backend_mut().set_capacity(Some(2));
+        for i in 0..3 {
+            assert!(io.fsync(&FD(3), i).is_ok());
+        }
+        assert_eq!(io.submit().ok(), Some(2));
+        assert_eq!(io.batched(), 1);
+        assert_eq!(io.submit().err().and_then(|e| e.raw_os_error()), Some(::libc::EAGAIN));
+
+        assert_eq!(io.results(2, 10, None).unwrap().len(), 2);
+        assert_eq!(io.submit().ok(), Some(1));
+    }
+
+    // Completion order with random delays and reordering
+    fn order(seed: u64) -> Vec<usize> {
+        let mut io : Ctx = Iocontext::with_backend(20, Mock::new(seed));
+
+        io.backend_mut().set_reorder(true);
+        io.backend_mut().set_error_rate(0.2, ::libc::EIO);
+        for i in 0..20 {
+            assert!(io.fsync(&FD(3), i).is_ok());
+        }
+        assert!(io.submit().is_ok());
+
+        reap_all(&mut io).into_iter().map(|(op, res)| match op {
+            IoOp::Fsync(i) => if res.is_ok() { i } else { 100 + i },
+            op => panic!("unexpected {:?}", op),
+        }).collect()
+    }
+
+    #[test]
+    fn deterministic() {
+        let a = order(42);
+
+        assert_eq!(a, order(42));
+        assert!(a != order(43));
+        assert!(a != (0..20).collect::<Vec<_>>());
+        assert!(a.iter().any(|&i| i >= 100));
+    }
+}
@@ -43,11 +43,35 @@ impl<T> Pool<T> {
     /// Free an index in the pool
     pub fn freeidx(&mut self, idx: usize) -> T {
         assert!(idx < self.pool.len());
+        if let Slot::Free(_) = self.pool[idx] {
+            panic!("Freeing free entry {}", idx)
+        }
+
+        // Link the entry to the old head of the freelist
+        let next = self.freelist;
         self.freelist = idx as isize;
         self.used -= 1;
-        match std::mem::replace(&mut self.pool[idx], Slot::Free(self.freelist)) {
+        match std::mem::replace(&mut self.pool[idx], Slot::Free(next)) {
             Slot::Alloc(v) => v,
-            Slot::Free(_) => panic!("Freeing free entry {}", idx)
+            Slot::Free(_) => unreachable!(),
+        }
+    }
+
+    /// Return a reference to an allocated entry, or None if `idx` is
+    /// free or out of range.
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        match self.pool.get(idx) {
+            Some(Slot::Alloc(t)) => Some(t),
+            _ => None,
+        }
+    }
+
+    /// Return a mutable reference to an allocated entry, or None if
+    /// `idx` is free or out of range.
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        match self.pool.get_mut(idx) {
+            Some(&mut Slot::Alloc(ref mut t)) => Some(t),
+            _ => None,
         }
     }
 
@@ -86,8 +110,8 @@ impl<T> Index<usize> for Pool<T> {
 impl<T> IndexMut<usize> for Pool<T> {
     fn index_mut(&mut self, idx: usize) -> &mut T {
         match &mut self.pool[idx] {
-            &mut Slot::Free(_) => panic!("access free index {}", idx),
-            &mut Slot::Alloc(ref mut t) => t
+            Slot::Free(_) => panic!("access free index {}", idx),
+            Slot::Alloc(t) => t
         }
     }
 }
@@ -168,6 +192,38 @@ mod test {
         }
     }
 
+    #[test]
+    fn reuse() {
+        let mut p = Pool::new(4);
+
+        for _ in 0..3 {
+            let mut v : Vec<_> = (0..4).map(|i| p.allocidx(i).ok().unwrap()).collect();
+            assert!(p.avail() == 0);
+
+            v.sort();
+            v.dedup();
+            assert_eq!(v.len(), 4);
+
+            for idx in v.into_iter() {
+                p.freeidx(idx);
+            }
+            assert!(p.avail() == 4);
+        }
+    }
+
+    #[test]
+    fn get() {
+        let mut p = Pool::new(4);
+
+        let idx = p.allocidx(7).ok().unwrap();
+        assert_eq!(p.get(idx), Some(&7));
+        assert_eq!(p.get(idx + 1), None);
+        assert_eq!(p.get(100), None);
+
+        p.freeidx(idx);
+        assert_eq!(p.get(idx), None);
+    }
+
     #[test]
     #[should_panic]
     fn badfree1() {
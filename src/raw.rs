@@ -2,38 +2,43 @@
 //! underlying libaio/kernel syscalls, and is intended as the building
 //! block for easier to use interfaces.
 
+// This predates field init shorthand and default binding modes, and
+// keeps to their explicit forms throughout.
+#![allow(clippy::redundant_field_names,
+         clippy::match_ref_pats,
+         clippy::needless_borrowed_reference)]
+
 extern crate std;
-extern crate eventfd;
-extern crate chrono;
 
 use std::io;
 use std::fmt::Debug;
+use std::any::{Any, TypeId};
 use std::default::Default;
-use std::os::unix::io::AsRawFd;
-use std::sync::mpsc::Receiver;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr;
+use std::mem;
+use std::ops::BitOr;
+use libc::{c_short, size_t};
 
-use self::chrono::duration::Duration;
+use std::time::{Duration, Instant};
 
-use super::Offset;
-use self::eventfd::EventFD;
+use super::{Offset, FD};
+use eventfd::EventFd;
 use pool::Pool;
+use stats::{Stats, OpKind};
+use backend::{Backend, Native};
 
 #[allow(dead_code)]
 use aioabi as aio;
 
 use buf::{RdBuf, WrBuf};
 
-struct Iocontextwrap {
-    ctx: aio::io_context_t,
-}
-
 /// Context for all AIO. This owns everything else, and must therefore
 /// have the longest lifetime. The type parameters are:
 ///
 /// * `T` - Every request carries a value of type T, which is returned
-///     with each IO result. This allows the caller to link requests to
-///     results.
+///   with each IO result. This allows the caller to link requests to
+///   results.
 ///
 /// * `Wb` - a write buffer type, which implements the `WrBuf` trait.
 ///
@@ -46,20 +51,129 @@ struct Iocontextwrap {
 /// Likewise, `T` is also copied by value, and so should be reasonably
 /// small.
 ///
+/// * `B` - the backend which actually performs the operations. By
+///   default this is the kernel's AIO, or io_uring if the `uring`
+///   feature is enabled and the kernel supports it; see the
+///   `backend` module.
+///
 /// Each operation takes ownership of the resources it needs, then
 /// returns them once the operation is complete, success or
 /// failure. This allows async IO to be used safely, as the borrow
 /// checker will make sure incomplete buffers are not accessible while
 /// they are being used.
-pub struct Iocontext<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send> {
-    ctx: Iocontextwrap,         // kernel AIO context
+pub struct Iocontext<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send, B: Backend = Native> {
+    ctx: B,                     // backend (normally kernel AIO context)
     maxops: usize,              // max batch size
 
     batch: Iobatch<T, Wb, Rb>,  // next batch to be submitted
 
-    evfd: Option<EventFD>,      // IO completion events
+    evfd: Option<EventFd>,      // IO completion events
 
     submitted: usize,           // number of submitted IO operations
+    failed: Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>, // requests rejected by submit
+    groups: Pool<Igroup<T, Wb, Rb>>, // groups with members outstanding
+    grouped: usize,             // group members waiting for a barrier
+    events: Vec<aio::Struct_io_event>, // buffer for reaping completions
+    seq: u64,                   // sequence number for Iohandles
+
+    ioprio: Option<Ioprio>,     // priority for new requests
+
+    drop_timeout: Option<Duration>, // max wait for outstanding ops on drop
+
+    policy: SubmitPolicy,       // when to submit automatically
+    batch_start: Option<Instant>, // when the oldest batched op was queued
+
+    stats: Option<Box<Stats>>,  // op statistics, if enabled
+}
+
+/// When a context submits batched operations by itself, without an
+/// explicit call to `submit`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SubmitPolicy {
+    /// Only submit when `submit` is called. This is the default.
+    Manual,
+
+    /// Submit each operation as soon as it's queued.
+    Immediate,
+
+    /// Submit once this many operations are batched.
+    Batch(usize),
+
+    /// Submit once the oldest batched operation has been waiting this
+    /// long. This is only checked when an operation is queued, so
+    /// there's no background timer.
+    Latency(Duration),
+
+    /// Submit when results are requested.
+    OnResults,
+}
+
+/// IO scheduling priority for a request. Levels range from 0
+/// (highest) to 7 (lowest).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Ioprio {
+    /// Realtime class. Requires `CAP_SYS_ADMIN`.
+    Realtime(u8),
+
+    /// Best-effort class, which most IO uses by default.
+    BestEffort(u8),
+
+    /// Idle class - only serviced when no other IO is pending.
+    Idle,
+}
+
+impl Ioprio {
+    // Value for aio_reqprio
+    fn value(&self) -> u16 {
+        let (class, level) = match *self {
+            Ioprio::Realtime(l) => (aio::IOPRIO_CLASS_RT, l),
+            Ioprio::BestEffort(l) => (aio::IOPRIO_CLASS_BE, l),
+            Ioprio::Idle => (aio::IOPRIO_CLASS_IDLE, 0),
+        };
+
+        (class << aio::IOPRIO_CLASS_SHIFT) | level as u16
+    }
+}
+
+/// Per-request flags for read and write operations. These can be
+/// combined with `|`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub struct RwFlags(u32);
+
+impl RwFlags {
+    /// High priority request, polling for completion if possible.
+    pub const HIPRI: RwFlags = RwFlags(aio::RWF_HIPRI);
+    /// Per-IO `O_DSYNC` - the write is durable when it completes.
+    pub const DSYNC: RwFlags = RwFlags(aio::RWF_DSYNC);
+    /// Per-IO `O_SYNC` - the write and metadata are durable when it completes.
+    pub const SYNC: RwFlags = RwFlags(aio::RWF_SYNC);
+    /// Fail with `EAGAIN` rather than blocking in submission.
+    pub const NOWAIT: RwFlags = RwFlags(aio::RWF_NOWAIT);
+    /// Per-IO `O_APPEND` - the offset is ignored.
+    pub const APPEND: RwFlags = RwFlags(aio::RWF_APPEND);
+
+    /// No flags.
+    pub fn empty() -> RwFlags { RwFlags(0) }
+
+    /// Raw `RWF_*` value.
+    pub fn bits(&self) -> u32 { self.0 }
+
+    /// Returns true if all the flags in `other` are set.
+    pub fn contains(&self, other: RwFlags) -> bool { self.0 & other.0 == other.0 }
+}
+
+impl BitOr for RwFlags {
+    type Output = RwFlags;
+
+    fn bitor(self, other: RwFlags) -> RwFlags { RwFlags(self.0 | other.0) }
+}
+
+/// Handle for a queued operation, returned when it's queued. This can
+/// be used to `cancel` the operation while it's still outstanding.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Iohandle {
+    idx: usize,                 // index of Iocb in pool
+    seq: u64,                   // sequence number, to detect reuse
 }
 
 
@@ -87,6 +201,256 @@ pub enum IoOp<T, Wb : WrBuf, Rb : RdBuf> {
     /// disk, but not necessarily metadata (timestamps, etc). Only
     /// works on some filesystems.
     Fdsync(T),                  // fdatasync
+
+    /// Poll - wait for a file descriptor to become ready. The result
+    /// is the mask of ready events.
+    Poll(T),
+
+    /// User-defined operation.
+    Op(Box<dyn Op>, T),
+
+    /// Group of operations, with each member's result.
+    Group(Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>, T),
+}
+
+/// A group of operations which complete as a unit, and can be ordered
+/// with barriers. This is queued with `Iocontext::group`.
+///
+/// Members added after a `barrier` are only issued once all the
+/// members before it have completed successfully, so for example a
+/// set of writes can be followed by an `fdsync` which is sure to
+/// cover them. If any member fails, the rest of the group is not
+/// issued.
+///
+/// The group completes with a single result: the sum of the members'
+/// results if they all succeeded, or otherwise the first error. The
+/// members are returned in an `IoOp::Group`, in the order they were
+/// added, along with their own results; members which were never
+/// issued have `ECANCELED`.
+pub struct Group<T, Wb : WrBuf, Rb : RdBuf> {
+    members: Vec<Member<T, Wb, Rb>>,
+    stages: Vec<usize>,         // index of first member after each barrier
+}
+
+struct Member<T, Wb : WrBuf, Rb : RdBuf> {
+    fd: RawFd,
+    off: Offset,
+    flags: RwFlags,
+    op: IoOp<T, Wb, Rb>,
+    res: Option<io::Result<usize>>,
+}
+
+impl<T, Wb : WrBuf, Rb : RdBuf> Default for Group<T, Wb, Rb> {
+    fn default() -> Group<T, Wb, Rb> { Group::new() }
+}
+
+impl<T, Wb : WrBuf, Rb : RdBuf> Group<T, Wb, Rb> {
+    /// Create a new empty group.
+    pub fn new() -> Group<T, Wb, Rb> {
+        Group { members: Vec::new(), stages: Vec::new() }
+    }
+
+    /// Number of operations in the group.
+    pub fn len(&self) -> usize { self.members.len() }
+
+    /// Returns true if the group has no operations.
+    pub fn is_empty(&self) -> bool { self.members.is_empty() }
+
+    fn add<F: AsRawFd>(&mut self, file: &F, off: Offset, flags: RwFlags, op: IoOp<T, Wb, Rb>) {
+        self.members.push(Member { fd: file.as_raw_fd(), off: off, flags: flags, op: op, res: None })
+    }
+
+    /// Add a barrier: operations added after it are only issued once
+    /// the ones before it are complete.
+    pub fn barrier(&mut self) {
+        let n = self.members.len();
+
+        // Empty stages are pointless
+        if n > 0 && self.stages.last() != Some(&n) {
+            self.stages.push(n)
+        }
+    }
+
+    /// Add a pread operation.
+    pub fn pread<F: AsRawFd>(&mut self, file: &F, buf: Rb, off: Offset, tok: T) {
+        self.add(file, off, RwFlags::empty(), IoOp::Pread(buf, tok))
+    }
+
+    /// Add a preadv operation.
+    pub fn preadv<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Rb>, off: Offset, tok: T) {
+        self.add(file, off, RwFlags::empty(), IoOp::Preadv(bufv, tok))
+    }
+
+    /// Add a pwrite operation.
+    pub fn pwrite<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: Offset, tok: T) {
+        self.pwrite_flags(file, buf, off, RwFlags::empty(), tok)
+    }
+
+    /// Add a pwrite operation with per-request `flags`.
+    pub fn pwrite_flags<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: Offset, flags: RwFlags, tok: T) {
+        self.add(file, off, flags, IoOp::Pwrite(buf, tok))
+    }
+
+    /// Add a pwritev operation.
+    pub fn pwritev<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) {
+        self.pwritev_flags(file, bufv, off, RwFlags::empty(), tok)
+    }
+
+    /// Add a pwritev operation with per-request `flags`.
+    pub fn pwritev_flags<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, flags: RwFlags, tok: T) {
+        self.add(file, off, flags, IoOp::Pwritev(bufv, tok))
+    }
+
+    /// Add an fsync operation.
+    pub fn fsync<F: AsRawFd>(&mut self, file: &F, tok: T) {
+        self.add(file, 0, RwFlags::empty(), IoOp::Fsync(tok))
+    }
+
+    /// Add an fdsync operation.
+    pub fn fdsync<F: AsRawFd>(&mut self, file: &F, tok: T) {
+        self.add(file, 0, RwFlags::empty(), IoOp::Fdsync(tok))
+    }
+
+    /// Add a user-defined operation.
+    pub fn op<F: AsRawFd>(&mut self, file: &F, op: Box<dyn Op>, tok: T) {
+        self.add(file, 0, RwFlags::empty(), IoOp::Op(op, tok))
+    }
+}
+
+// A group which has been queued.
+struct Igroup<T, Wb : WrBuf, Rb : RdBuf> {
+    group: Group<T, Wb, Rb>,
+    tok: T,
+    next: usize,                // first member not yet issued
+    inflight: usize,            // members issued but not complete
+    res: io::Result<usize>,     // combined result so far
+}
+
+impl<T, Wb : WrBuf, Rb : RdBuf> Igroup<T, Wb, Rb> {
+    // Range of members in the next stage to be issued.
+    fn stage(&self) -> std::ops::Range<usize> {
+        let end = self.group.stages.iter().cloned().find(|&s| s > self.next)
+            .unwrap_or(self.group.members.len());
+
+        self.next..end
+    }
+
+    fn record(&mut self, res: &io::Result<usize>) {
+        self.res = match (&self.res, res) {
+            (&Ok(tot), &Ok(n)) => Ok(tot + n),
+            (&Ok(_), &Err(ref e)) => Err(copy_error(e)),
+            (&Err(ref e), _) => Err(copy_error(e)),
+        }
+    }
+}
+
+// io::Error isn't Clone.
+fn copy_error(e: &io::Error) -> io::Error {
+    match e.raw_os_error() {
+        Some(errno) => io::Error::from_raw_os_error(errno),
+        None => io::Error::new(e.kind(), e.to_string()),
+    }
+}
+
+/// A user-defined operation, which can be queued with
+/// `Iocontext::op`. This allows operations the context doesn't
+/// directly support to share the same context and completion queue.
+///
+/// The operation owns whatever resources it needs while it's in
+/// flight, and is handed back in an `IoOp::Op` once it completes; use
+/// `downcast` to recover the concrete type.
+pub trait Op: Any + Send {
+    /// Fill in the operation-specific parts of the kernel control
+    /// block: at least the opcode, and typically the buffer, count
+    /// and offset. The file descriptor, eventfd and priority are
+    /// already set up, and `data` must not be changed.
+    ///
+    /// Any memory referenced by the iocb must stay valid until the
+    /// operation completes, so should be owned by the operation.
+    fn prep(&mut self, iocb: &mut aio::Struct_iocb);
+
+    /// Called with the kernel's result when the operation completes,
+    /// returning the result to report. By default it's unchanged.
+    fn complete(&mut self, res: io::Result<usize>) -> io::Result<usize> { res }
+}
+
+impl dyn Op {
+    /// Recover the concrete type of a completed operation.
+    pub fn downcast<O: Op>(self: Box<Self>) -> Result<Box<O>, Box<dyn Op>> {
+        if (*self).type_id() == TypeId::of::<O>() {
+            let any : Box<dyn Any> = self;
+            Ok(any.downcast().ok().unwrap())
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<T, Wb : WrBuf, Rb : RdBuf> IoOp<T, Wb, Rb> {
+    // Kind of operation for statistics. Groups aren't counted as such,
+    // only their members.
+    fn kind(&self) -> Option<OpKind> {
+        match *self {
+            IoOp::Noop => Some(OpKind::Noop),
+            IoOp::Pread(..) => Some(OpKind::Pread),
+            IoOp::Preadv(..) => Some(OpKind::Preadv),
+            IoOp::Pwrite(..) => Some(OpKind::Pwrite),
+            IoOp::Pwritev(..) => Some(OpKind::Pwritev),
+            IoOp::Fsync(..) => Some(OpKind::Fsync),
+            IoOp::Fdsync(..) => Some(OpKind::Fdsync),
+            IoOp::Poll(..) => Some(OpKind::Poll),
+            IoOp::Op(..) => Some(OpKind::Op),
+            IoOp::Group(..) => None,
+        }
+    }
+
+    // Fill in the operation-specific parts of an iocb, returning the
+    // iovec array it needs, if any.
+    fn prep(&mut self, iocb: &mut aio::Struct_iocb) -> Option<Iovecs> {
+        let opcode = match *self {
+            IoOp::Noop => aio::Iocmd::IO_CMD_NOOP,
+            IoOp::Pread(ref mut buf, _) => {
+                let b = buf.rdbuf();
+                iocb.aio_buf = b.as_ptr() as u64;
+                iocb.aio_count = b.len() as u64;
+                aio::Iocmd::IO_CMD_PREAD
+            },
+            IoOp::Pwrite(ref buf, _) => {
+                let b = buf.wrbuf();
+                iocb.aio_buf = b.as_ptr() as u64;
+                iocb.aio_count = b.len() as u64;
+                aio::Iocmd::IO_CMD_PWRITE
+            },
+            // aio_buf is set to the iovec array once the Iocb is in place
+            IoOp::Preadv(ref mut bufv, _) => {
+                let iov = Iovecs::new(bufv.iter_mut()
+                                      .map(|b| aio::Struct_iovec { iov_base: b.rdbuf().as_mut_ptr(),
+                                                                   iov_len: b.rdbuf().len() as size_t }));
+                iocb.aio_lio_opcode = aio::Iocmd::IO_CMD_PREADV as u16;
+                iocb.aio_count = iov.len() as u64;
+                return Some(iov)
+            },
+            IoOp::Pwritev(ref bufv, _) => {
+                let iov = Iovecs::new(bufv.iter()
+                                      .map(|b| aio::Struct_iovec { iov_base: b.wrbuf().as_ptr() as *mut u8,
+                                                                   iov_len: b.wrbuf().len() as size_t }));
+                iocb.aio_lio_opcode = aio::Iocmd::IO_CMD_PWRITEV as u16;
+                iocb.aio_count = iov.len() as u64;
+                return Some(iov)
+            },
+            IoOp::Fsync(_) => aio::Iocmd::IO_CMD_FSYNC,
+            IoOp::Fdsync(_) => aio::Iocmd::IO_CMD_FDSYNC,
+            IoOp::Poll(_) => aio::Iocmd::IO_CMD_POLL,   // events are already in aio_buf
+            IoOp::Op(ref mut op, _) => {
+                op.prep(iocb);
+                return None
+            },
+            IoOp::Group(..) => panic!("group can't be issued as a single op"),
+        };
+
+        iocb.aio_lio_opcode = opcode as u16;
+        None
+    }
 }
 
 fn as_mut_ptr<T>(thing: Option<&mut T>) -> *mut T {
@@ -99,79 +463,275 @@ fn as_mut_ptr<T>(thing: Option<&mut T>) -> *mut T {
 #[allow(dead_code)]
 fn as_ptr<T>(thing: Option<&T>) -> *const T {
     match thing {
-        None => 0 as *const T,
+        None => ptr::null(),
         Some(t) => t as *const T
     }
 }
 
-fn timespec_from_duration(dur: Duration) -> aio::timespec {
-    let dur = std::cmp::max(Duration::zero(), dur); // -ve time is 0
-
-    aio::timespec { tv_sec: dur.num_seconds(), tv_nsec: dur.num_nanoseconds().unwrap() % 1_000_000_000 }
+// Note the submission time of some iocbs, for statistics.
+fn set_issued<T, Wb : WrBuf, Rb : RdBuf>(iocbp: &[*mut aio::Struct_iocb], now: Instant) {
+    for &p in iocbp {
+        unsafe { (*((*p).data as *mut Iocb<T, Wb, Rb>)).issued = Some(now) }
+    }
 }
 
 
 impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
-    /// Instantiate a new Iocontext. `maxops` is the maximum number of
-    /// outstanding operations, which sets the upper limit on memory
-    /// allocated.
+    /// Instantiate a new Iocontext using the native backend. `maxops`
+    /// is the maximum number of outstanding operations, which sets the
+    /// upper limit on memory allocated.
     pub fn new(maxops: usize) -> io::Result<Iocontext<T, Wb, Rb>> {
-        let mut r = Iocontext {
-            ctx: Iocontextwrap { ctx: ptr::null_mut() },
+        match Native::new(maxops) {
+            Err(e) => Err(e),
+            Ok(k) => Ok(Iocontext::with_backend(maxops, k)),
+        }
+    }
+}
+
+impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send, B: Backend> Iocontext<T, Wb, Rb, B> {
+    /// Instantiate a new Iocontext using `backend` to perform the
+    /// operations, with up to `maxops` outstanding.
+    pub fn with_backend(maxops: usize, backend: B) -> Iocontext<T, Wb, Rb, B> {
+        Iocontext {
+            ctx: backend,
             maxops: maxops,
             batch: Iobatch::new(maxops),
             evfd: None,
             submitted: 0,
-        };
-        let e = unsafe { aio::io_queue_init(maxops as i32, &mut r.ctx.ctx) };
-
-        if e < 0 {
-            Err(io::Error::from_raw_os_error(e))
-        } else {
-            Ok(r)
+            failed: Vec::new(),
+            groups: Pool::new(maxops),
+            grouped: 0,
+            events: (0..maxops).map(|_| Default::default()).collect(),
+            seq: 0,
+            ioprio: None,
+            drop_timeout: None,
+            policy: SubmitPolicy::Manual,
+            batch_start: None,
+            stats: None,
         }
     }
 
-    // XXX how to make crate-local?
-    #[doc(hidden)]
-    pub fn get_evfd_stream(&mut self) -> io::Result<Receiver<u64>> {
+    /// Return the backend.
+    pub fn backend(&self) -> &B { &self.ctx }
+
+    /// Return the backend, mutably. Operations must not be submitted
+    /// directly to it.
+    pub fn backend_mut(&mut self) -> &mut B { &mut self.ctx }
+
+    /// Return an eventfd which is signalled as operations complete,
+    /// creating it on the first call. With kernel AIO, operations
+    /// queued before then don't signal it; other backends may signal
+    /// it for those too, or when there's nothing to reap (see
+    /// `uring::Native`).
+    ///
+    /// The eventfd can be registered with `epoll` or an event loop
+    /// instead of blocking in `results`. When it's readable, `drain`
+    /// it and then collect results with `try_results` until there are
+    /// none left; draining first means anything which completes in the
+    /// meantime makes it readable again.
+    pub fn eventfd(&mut self) -> io::Result<&EventFd> {
         if self.evfd.is_none() {
-            match EventFD::new(0, 0) {
+            match EventFd::new() {
                 Err(e) => return Err(e),
-                Ok(evfd) => self.evfd = Some(evfd),
+                Ok(evfd) => match self.ctx.set_eventfd(evfd.as_raw_fd()) {
+                    Err(e) => return Err(e),
+                    Ok(()) => self.evfd = Some(evfd),
+                },
             }
-
         }
 
-        Ok(self.evfd.as_ref().unwrap().events())
+        Ok(self.evfd.as_ref().unwrap())
+    }
+
+    // Signal the eventfd for results which don't come from the
+    // backend, such as rejected requests.
+    fn notify(&self) {
+        if let Some(ref evfd) = self.evfd {
+            let _ = evfd.signal(1);
+        }
     }
 
     /// Submit all outstanding IO operations. Returns number of submitted operations.
+    ///
+    /// This keeps submitting until the whole batch has been accepted.
+    /// If the kernel rejects an individual request, it is failed with
+    /// the kernel's error and returned, along with its resources, by
+    /// the next call to `results`; the rest of the batch is still
+    /// submitted. If the kernel is out of resources (`EAGAIN`), the
+    /// remaining requests stay batched for a later submit; this is
+    /// only returned as an error if nothing could be submitted.
     pub fn submit(&mut self) -> io::Result<usize> {
-        // Get the current batch and clear out the new one
-        let mut iocbp = self.batch.batch();
+        let mut total = 0;
 
-        if iocbp.len() == 0 {
-            Ok(0)
-        } else {
-            let r = unsafe { aio::io_submit(self.ctx.ctx, iocbp.len() as i64, iocbp.as_mut_ptr()) };
-
-            if r < 0 {
-                Err(io::Error::from_raw_os_error(-r))
-            } else {
-                let ru = r as usize;
+        while self.batch.len() > 0 {
+            let r = unsafe { self.ctx.submit(&self.batch.batch()[..]) };
+            let mut stalled = false;
 
-                // XXX need a Vec method to remove a range
-                for _ in 0..r {
-                    if iocbp.remove(0).is_null() {
-                        break;
+            match r {
+                Ok(ru) if ru > 0 => {
+                    if self.stats.is_some() {
+                        set_issued::<T, Wb, Rb>(&self.batch.batch()[..ru], Instant::now())
                     }
+                    self.batch.batch().drain(..ru);
+                    self.submitted += ru;
+                    total += ru;
+                },
+                Ok(_) => stalled = true,
+                Err(ref e) if e.raw_os_error() == Some(::libc::EAGAIN) => stalled = true,
+                Err(e) => {
+                    // The first request in the batch was rejected
+                    let iocbp = self.batch.batch().remove(0);
+                    let iocb = unsafe { (*iocbp).data } as *mut Iocb<T, Wb, Rb>;
+                    let Iocb { op, group, queued, .. } = self.batch.free_iocb(iocb);
+                    let mut failed = mem::take(&mut self.failed);
+                    let res = Err(e);
+
+                    self.account(&op, &res, queued, None);
+                    self.finish(op, res, group, &mut |op, res| failed.push((op, res)));
+                    self.failed = failed;
+                    self.notify();
+                },
+            }
+
+            if stalled {
+                if total == 0 {
+                    return Err(io::Error::from_raw_os_error(::libc::EAGAIN))
                 }
-                self.submitted += ru;
+                break
+            }
+        }
+
+        if self.batch.len() == 0 {
+            self.batch_start = None
+        }
+        Ok(total)
+    }
+
+    /// Set when batched operations are submitted without an explicit
+    /// `submit`. With any policy other than `Manual`, anything still
+    /// batched is also submitted before results are reaped, so
+    /// operations aren't left unsubmitted while waiting for them.
+    ///
+    /// Errors from automatic submission are handled as for `submit`:
+    /// rejected requests are returned by `results`, and requests the
+    /// kernel has no room for stay batched.
+    pub fn set_submit_policy(&mut self, policy: SubmitPolicy) {
+        self.policy = policy;
+        if self.batch.len() > 0 {
+            self.queued()
+        }
+    }
+
+    /// Return the current submission policy.
+    pub fn submit_policy(&self) -> SubmitPolicy { self.policy }
+
+    // Called after batching operations, to submit them if the policy
+    // says so.
+    fn queued(&mut self) {
+        let now = Instant::now();
+        let start = *self.batch_start.get_or_insert(now);
+
+        let go = match self.policy {
+            SubmitPolicy::Manual | SubmitPolicy::OnResults => false,
+            SubmitPolicy::Immediate => true,
+            SubmitPolicy::Batch(n) => self.batch.len() >= n,
+            SubmitPolicy::Latency(budget) => now - start >= budget,
+        };
+
+        if go {
+            let _ = self.submit();
+        }
+    }
+
+    // Submit the next stage of a group as soon as it's ready, rather
+    // than waiting for the next `submit`.
+    fn submit_stage<F>(&mut self, mut iocbp: Vec<*mut aio::Struct_iocb>, f: &mut F) -> usize
+        where F: FnMut(IoOp<T, Wb, Rb>, io::Result<usize>)
+    {
+        let mut done = 0;
+
+        while !iocbp.is_empty() {
+            let r = unsafe { self.ctx.submit(&iocbp[..]) };
+            let mut stalled = false;
+
+            match r {
+                Ok(n) if n > 0 => {
+                    if self.stats.is_some() {
+                        set_issued::<T, Wb, Rb>(&iocbp[..n], Instant::now())
+                    }
+                    iocbp.drain(..n);
+                    self.submitted += n;
+                },
+                Ok(_) => stalled = true,
+                Err(ref e) if e.raw_os_error() == Some(::libc::EAGAIN) => stalled = true,
+                Err(e) => {
+                    let iocb = unsafe { (*iocbp.remove(0)).data } as *mut Iocb<T, Wb, Rb>;
+                    let Iocb { op, group, queued, .. } = self.batch.free_iocb(iocb);
+                    let res = Err(e);
+
+                    self.account(&op, &res, queued, None);
+                    done += self.finish(op, res, group, f);
+                },
+            }
 
-                Ok(ru)
+            if stalled {
+                // Leave the rest for the next submit
+                let rest = mem::replace(self.batch.batch(), iocbp);
+                self.batch.batch().extend(rest);
+                break
             }
         }
+
+        done
+    }
+
+    // Update statistics for a finished operation.
+    fn account(&mut self, op: &IoOp<T, Wb, Rb>, res: &io::Result<usize>,
+               queued: Option<Instant>, issued: Option<Instant>) {
+        let (stats, kind) = match (self.stats.as_mut(), op.kind()) {
+            (Some(stats), Some(kind)) => (stats, kind),
+            _ => return,
+        };
+        let s = stats.op_mut(kind);
+
+        s.completed += 1;
+        match *res {
+            Ok(n) => match kind {
+                OpKind::Pread | OpKind::Preadv | OpKind::Pwrite | OpKind::Pwritev => s.bytes += n as u64,
+                _ => (),
+            },
+            Err(ref e) => *s.errors.entry(e.raw_os_error().unwrap_or(0)).or_insert(0) += 1,
+        }
+
+        match (queued, issued) {
+            (Some(queued), Some(issued)) => s.queue_time.record(issued - queued),
+            // Rejected when it was submitted
+            (Some(queued), None) => s.queue_time.record(queued.elapsed()),
+            _ => (),
+        }
+        if let Some(issued) = issued {
+            s.latency.record(issued.elapsed())
+        }
+    }
+
+    /// Enable or disable collecting statistics about completed
+    /// operations. Only operations queued while statistics are
+    /// enabled have their times recorded. Disabling statistics
+    /// discards them.
+    pub fn set_stats(&mut self, enable: bool) {
+        if !enable {
+            self.stats = None
+        } else if self.stats.is_none() {
+            self.stats = Some(Box::new(Stats::new()))
+        }
+    }
+
+    /// Return a snapshot of the statistics, if enabled.
+    pub fn stats(&self) -> Option<Stats> { self.stats.as_ref().map(|s| (**s).clone()) }
+
+    /// Return the statistics and reset them, if enabled.
+    pub fn take_stats(&mut self) -> Option<Stats> {
+        self.stats.as_mut().map(|s| mem::replace(&mut **s, Stats::new()))
     }
 
     /// Return number of batched entries for the next submission.
@@ -180,8 +740,13 @@ impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
     /// Number of outstanding submitted ops.
     pub fn submitted(&self) -> usize { self.submitted }
 
-    /// Total number of pending operations, batched and submitted.
-    pub fn pending(&self) -> usize { self.batched() + self.submitted() }
+    /// Number of requests which failed submission, whose results
+    /// have yet to be returned by `results`.
+    pub fn failed(&self) -> usize { self.failed.len() }
+
+    /// Total number of pending operations: batched, submitted, failed
+    /// but not yet returned, and group members waiting for a barrier.
+    pub fn pending(&self) -> usize { self.batched() + self.submitted() + self.failed() + self.grouped }
 
     /// Return max number pending of operations.
     pub fn maxops(&self) -> usize { self.maxops }
@@ -190,177 +755,547 @@ impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send> Iocontext<T, Wb, Rb> {
     /// pending operations.
     pub fn full(&self) -> bool { self.pending() >= self.maxops }
 
+    /// Set the IO priority for operations queued from now on. `None`
+    /// uses the submitting thread's priority.
+    pub fn set_ioprio(&mut self, prio: Option<Ioprio>) {
+        match prio {
+            Some(Ioprio::Realtime(l)) | Some(Ioprio::BestEffort(l)) =>
+                assert!(l < 8, "ioprio level {} out of range", l),
+            _ => (),
+        }
+        self.ioprio = prio
+    }
+
+    /// Return the IO priority for newly queued operations.
+    pub fn ioprio(&self) -> Option<Ioprio> { self.ioprio }
+
+    /// Set how long dropping the context waits for outstanding
+    /// operations to complete. `None`, the default, waits for all of
+    /// them.
+    ///
+    /// If the timeout expires with operations still in flight, their
+    /// buffers are leaked rather than freed, since the kernel may
+    /// still be using them.
+    pub fn set_drop_timeout(&mut self, timeout: Option<Duration>) { self.drop_timeout = timeout }
+
+    /// Shut down the context, returning all the operations which
+    /// could be recovered. Operations which were never submitted are
+    /// returned with `ECANCELED`; submitted operations are cancelled
+    /// if possible, and their results are waited for up to
+    /// `timeout` (forever if `None`).
+    ///
+    /// Any operations still in flight when the timeout expires are
+    /// leaked, as with `set_drop_timeout`.
+    pub fn shutdown(mut self, timeout: Option<Duration>) -> Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)> {
+        let ret = self.drain(timeout);
+
+        // Don't wait again when dropped
+        self.drop_timeout = Some(Duration::from_secs(0));
+        ret
+    }
+
+    // Cancel everything outstanding and wait for up to `timeout` for
+    // the results of anything which couldn't be cancelled.
+    fn drain(&mut self, timeout: Option<Duration>) -> Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)> {
+        let canceled = || Err(io::Error::from_raw_os_error(::libc::ECANCELED));
+        let mut ret = Vec::new();
+
+        // Don't issue any more group stages
+        for idx in 0..self.maxops {
+            if let Some(g) = self.groups.get_mut(idx) {
+                if g.res.is_ok() {
+                    g.res = canceled()
+                }
+            }
+        }
+
+        let batch : Vec<_> = self.batch.batch().drain(..).collect();
+        for iocbp in batch.into_iter() {
+            let iocb = unsafe { (*iocbp).data } as *mut Iocb<T, Wb, Rb>;
+            let Iocb { op, group, .. } = self.batch.free_iocb(iocb);
+
+            self.finish(op, canceled(), group, &mut |op, res| ret.push((op, res)));
+        }
+        ret.append(&mut self.failed);
+
+        // Everything left in the pool has been submitted
+        for idx in 0..self.maxops {
+            let iocb = match self.batch.get(idx) {
+                None => continue,
+                Some(iocb) => iocb as *const Iocb<T, Wb, Rb> as *mut Iocb<T, Wb, Rb>,
+            };
+            if unsafe { self.ctx.cancel(&mut (*iocb).iocb) }.is_ok() {
+                let Iocb { op, group, .. } = self.batch.free_iocb(iocb);
+
+                self.submitted -= 1;
+                self.finish(op, canceled(), group, &mut |op, res| ret.push((op, res)));
+            }
+        }
+
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+
+        while self.submitted > 0 {
+            let want = self.submitted;
+
+            if self.results_into(want, self.maxops, deadline, &mut ret).is_err() {
+                break
+            }
+            if self.submitted > 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+                break           // timed out
+            }
+        }
+
+        ret
+    }
+
     /// Return a vector of IO results. Each result return the `T` and
     /// buffer used for IO so the caller can use it again, and the
     /// actual result of the IO.
+    ///
+    /// This waits for up to `timeout` for at least `min` results, or
+    /// forever if `timeout` is `None`.
+    ///
+    /// Completions are reaped directly from the kernel's completion
+    /// ring where possible; the `io_getevents` syscall is only used
+    /// when fewer than `min` results are available and it must block.
+    #[allow(clippy::type_complexity)]
     pub fn results(&mut self, min: usize, max: usize, timeout: Option<Duration>)
                    -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
-        let mut v : Vec<_> = (0..max).map(|_| Default::default()).collect();
-        let r = unsafe {
-            let mut ts = timeout.map(timespec_from_duration);
-            aio::io_getevents(self.ctx.ctx, min as i64, max as i64, v.as_mut_ptr(), as_mut_ptr(ts.as_mut()))
+        // A timeout too large to represent is as good as forever
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+
+        self.results_deadline(min, max, deadline)
+    }
+
+    /// Return a vector of IO results, like `results`, but waiting
+    /// until an absolute `deadline` rather than for a timeout.
+    ///
+    /// If the wait is interrupted by a signal it is resumed with the
+    /// remaining time, so signals don't extend the overall wait.
+    #[allow(clippy::type_complexity)]
+    pub fn results_deadline(&mut self, min: usize, max: usize, deadline: Option<Instant>)
+                            -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
+        let mut ret = Vec::with_capacity(max);
+
+        match self.results_into(min, max, deadline, &mut ret) {
+            Err(e) => Err(e),
+            Ok(_) => Ok(ret),
+        }
+    }
+
+    /// Return a vector of any IO results which are immediately
+    /// available, without blocking. This never enters the kernel if
+    /// the completion ring is usable.
+    #[allow(clippy::type_complexity)]
+    pub fn try_results(&mut self, max: usize)
+                       -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
+        self.results_deadline(0, max, Some(Instant::now()))
+    }
+
+    /// Append IO results to `out`, waiting until `deadline` for at
+    /// least `min` of them. Returns the number of results added.
+    ///
+    /// This doesn't allocate if `out` has enough spare capacity, so
+    /// the same vector can be reused for every batch of results.
+    #[allow(clippy::type_complexity)]
+    pub fn results_into(&mut self, min: usize, max: usize, deadline: Option<Instant>,
+                        out: &mut Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>) -> io::Result<usize> {
+        self.results_with(min, max, deadline, |op, res| out.push((op, res)))
+    }
+
+    /// Pass IO results to `f` as they're reaped, waiting until
+    /// `deadline` for at least `min` of them. Returns the number of
+    /// results passed to `f`. This never allocates, except when
+    /// issuing the next stage of a group.
+    ///
+    /// `min` and `max` count completions, including completions of
+    /// group members which don't yet complete their group; so fewer
+    /// than `min` results may be returned.
+    ///
+    /// An error is only returned if no results were reaped.
+    pub fn results_with<F>(&mut self, min: usize, max: usize, deadline: Option<Instant>, mut f: F) -> io::Result<usize>
+        where F: FnMut(IoOp<T, Wb, Rb>, io::Result<usize>)
+    {
+        if self.policy != SubmitPolicy::Manual && self.batch.len() > 0 {
+            let _ = self.submit();
+        }
+
+        let nfailed = std::cmp::min(max, self.failed.len());
+        for (op, res) in self.failed.drain(..nfailed) {
+            f(op, res)
+        }
+
+        let (min, max) = (min.saturating_sub(nfailed),
+                          std::cmp::min(max - nfailed, self.maxops));
+
+        // Reuse the context's event buffer
+        let mut v = mem::take(&mut self.events);
+
+        let (mut n, wait) = match self.ctx.reap(&mut v[..max]) {
+            Some(n) => (n, n < min),
+            None => (0, true),
         };
+        let mut err = None;
 
-        if r < 0 {
-            Err(io::Error::from_raw_os_error(-r))
+        if wait {
+            match self.ctx.getevents(min - n, &mut v[n..max], deadline) {
+                Err(e) => err = Some(e),
+                Ok(r) => n += r,
+            }
+        }
+
+        let done = self.complete(&v[..n], &mut f);
+        self.events = v;
+
+        match err {
+            // Don't lose anything already reaped
+            Some(e) if n + nfailed == 0 => Err(e),
+            _ => Ok(done + nfailed),
+        }
+    }
+
+    // Convert completion events into results, freeing their
+    // iocbs. Returns the number of results passed to `f`.
+    fn complete<F>(&mut self, events: &[aio::Struct_io_event], f: &mut F) -> usize
+        where F: FnMut(IoOp<T, Wb, Rb>, io::Result<usize>)
+    {
+        let mut done = 0;
+
+        for ev in events.iter() {
+            let evres = if ev.res < 0 {
+                Err(io::Error::from_raw_os_error(-ev.res as i32))
+            } else {
+                Ok(ev.res as usize)
+            };
+            let iocb = ev.data as *mut Iocb<T, Wb, Rb>;
+            let Iocb { op, group, queued, issued, .. } = self.batch.free_iocb(iocb);
+            let (op, evres) = match op {
+                IoOp::Op(mut op, tok) => {
+                    let evres = op.complete(evres);
+                    (IoOp::Op(op, tok), evres)
+                },
+                op => (op, evres),
+            };
+
+            self.submitted -= 1;
+            self.account(&op, &evres, queued, issued);
+            done += self.finish(op, evres, group, f);
+        }
+
+        done
+    }
+
+    // Deal with a finished operation. If it's a group member the
+    // group is updated, and either its next stage is issued or the
+    // whole group is finished. Returns the number of results passed
+    // to `f`.
+    fn finish<F>(&mut self, op: IoOp<T, Wb, Rb>, res: io::Result<usize>,
+                 group: Option<(usize, usize)>, f: &mut F) -> usize
+        where F: FnMut(IoOp<T, Wb, Rb>, io::Result<usize>)
+    {
+        let (gidx, m) = match group {
+            None => { f(op, res); return 1 },
+            Some(g) => g,
+        };
+
+        let ready = {
+            let g = self.groups.get_mut(gidx).expect("missing group");
+
+            g.record(&res);
+            g.group.members[m].op = op;
+            g.group.members[m].res = Some(res);
+            g.inflight -= 1;
+
+            if g.inflight > 0 {
+                return 0
+            }
+            g.res.is_ok() && g.next < g.group.members.len()
+        };
+
+        if ready {
+            let iocbp = self.stage(gidx);
+            self.submit_stage(iocbp, f)
         } else {
-            v.truncate(r as usize);
-            let ret = v.iter()
-                .map(|ev| {
-                    let evres = if ev.res < 0 {
-                        Err(io::Error::from_raw_os_error(-ev.res as i32))
-                    } else {
-                        Ok(ev.res as usize)
-                    };
-                    let iocb = ev.data as *mut Iocb<T, Wb, Rb>;
-                    
-                    self.submitted -= 1;
-                    (self.batch.free_iocb(iocb).op, evres)
-                })
+            let g = self.groups.freeidx(gidx);
+            let canceled = || Err(io::Error::from_raw_os_error(::libc::ECANCELED));
+            let unissued = g.group.members.len() - g.next;
+            let members = g.group.members.into_iter()
+                .map(|m| (m.op, m.res.unwrap_or_else(canceled)))
+                .collect();
+
+            self.grouped -= unissued;
+            f(IoOp::Group(members, g.tok), g.res);
+            1
+        }
+    }
+
+    // Allocate iocbs for the next stage of a group, returning them
+    // for submission.
+    fn stage(&mut self, gidx: usize) -> Vec<*mut aio::Struct_iocb> {
+        let (range, members) = {
+            let g = self.groups.get_mut(gidx).expect("missing group");
+            let range = g.stage();
+            let members : Vec<_> = g.group.members[range.clone()].iter_mut()
+                .map(|m| (m.fd, m.off, m.flags, mem::replace(&mut m.op, IoOp::Noop)))
                 .collect();
-            Ok(ret)
+
+            g.next = range.end;
+            g.inflight = range.len();
+            (range, members)
+        };
+
+        self.grouped -= range.len();
+        members.into_iter().zip(range)
+            .map(|((fd, off, flags, op), m)| {
+                let iocb = aio::Struct_iocb { aio_rw_flags: flags.bits(), .. self.pack_iocb(&FD(fd), off) };
+                let (_, iocb) = self.place(iocb, op, Some((gidx, m)));
+                unsafe { &mut (*iocb).iocb as *mut aio::Struct_iocb }
+            })
+            .collect()
+    }
+
+    /// Queue up a group of operations. The group's first stage (up to
+    /// its first barrier) is batched for the next `submit`; each later
+    /// stage is submitted as soon as the previous one completes, while
+    /// reaping results.
+    ///
+    /// The group needs room for all its members, even though they
+    /// aren't all issued at once; if there isn't enough the group is
+    /// returned. It can't be cancelled, except by dropping or shutting
+    /// down the context.
+    pub fn group(&mut self, group: Group<T, Wb, Rb>, tok: T) -> Result<(), (Group<T, Wb, Rb>, T)> {
+        let n = group.len();
+
+        if self.pending() + std::cmp::max(n, 1) > self.maxops {
+            return Err((group, tok))
         }
+        if n == 0 {
+            self.failed.push((IoOp::Group(Vec::new(), tok), Ok(0)));
+            self.notify();
+            return Ok(())
+        }
+
+        let ig = Igroup { group: group, tok: tok, next: 0, inflight: 0, res: Ok(0) };
+        let gidx = match self.groups.allocidx(ig) {
+            Err(_) => panic!("group alloc failed but not full"),
+            Ok(idx) => idx,
+        };
+
+        self.grouped += n;
+        let iocbp = self.stage(gidx);
+        self.batch.batch().extend(iocbp);
+        self.queued();
+        Ok(())
     }
 
-    fn pack_iocb<F: AsRawFd>(&self, opcode: aio::Iocmd, file: &F, off: Offset) -> aio::Struct_iocb {
+    // Common parts of an iocb for an operation on `file`.
+    fn pack_iocb<F: AsRawFd>(&self, file: &F, off: Offset) -> aio::Struct_iocb {
         aio::Struct_iocb {
-            aio_lio_opcode: opcode as u16,
             aio_fildes: file.as_raw_fd() as u32,
             aio_offset: off,
-            aio_flags: self.evfd.as_ref().map_or(0, |_| aio::IOCB_FLAG_RESFD),
+            aio_flags: self.evfd.as_ref().map_or(0, |_| aio::IOCB_FLAG_RESFD) |
+                       self.ioprio.map_or(0, |_| aio::IOCB_FLAG_IOPRIO),
             aio_resfd: self.evfd.as_ref().map_or(0, |evfd| evfd.as_raw_fd() as u32),
+            aio_reqprio: self.ioprio.map_or(0, |p| p.value()),
             data: 0,
 
             ..Default::default()
         }
     }
 
-    fn prep_iocb<E>(&mut self, iocb: Iocb<T, Wb, Rb>) -> Result<(), E> {
-        match self.batch.alloc_iocb(iocb) {
+    // Add an operation to the batch. The caller must have checked
+    // there's room for it.
+    fn queue(&mut self, iocb: aio::Struct_iocb, op: IoOp<T, Wb, Rb>) -> Iohandle {
+        let (h, iocb) = self.place(iocb, op, None);
+
+        self.batch.batch().push(unsafe { &mut (*iocb).iocb });
+        self.queued();
+        h
+    }
+
+    // Set up an operation in its final place in the pool, without
+    // batching it.
+    fn place(&mut self, mut iocb: aio::Struct_iocb, mut op: IoOp<T, Wb, Rb>,
+             group: Option<(usize, usize)>) -> (Iohandle, *mut Iocb<T, Wb, Rb>) {
+        let iov = op.prep(&mut iocb);
+
+        self.seq += 1;
+
+        let queued = self.stats.as_ref().map(|_| Instant::now());
+        let iocb = Iocb { iocb: iocb, op: op, seq: self.seq, iov: iov, group: group,
+                          queued: queued, issued: None };
+
+        match self.batch.alloc(iocb) {
             Err(_) => panic!("alloc failed but not full"),
-            Ok(iocb) => unsafe { (*iocb).iocb.data = iocb as u64; Ok(()) },
+            Ok((idx, iocb)) => unsafe {
+                (*iocb).iocb.data = iocb as u64;
+                if let Some(ref iov) = (*iocb).iov {
+                    (*iocb).iocb.aio_buf = iov.as_slice().as_ptr() as u64;
+                }
+                (Iohandle { idx: idx, seq: self.seq }, iocb)
+            },
+        }
+    }
+
+    /// Cancel an outstanding operation, returning its resources.
+    ///
+    /// An operation which has only been batched is simply removed
+    /// from the batch. Otherwise the kernel is asked to cancel it,
+    /// which can fail with:
+    ///
+    /// * `EINPROGRESS` - cancellation has been started, and the
+    ///   operation will be returned by `results` as usual.
+    /// * `EAGAIN` - the operation could not be cancelled.
+    /// * `EINVAL` - the operation can't be cancelled, or has already
+    ///   completed. This is also returned if `h` doesn't refer to an
+    ///   outstanding operation.
+    ///
+    /// In all the error cases the operation's result will still be
+    /// returned by `results`.
+    pub fn cancel(&mut self, h: Iohandle) -> io::Result<IoOp<T, Wb, Rb>> {
+        let iocb = match self.batch.get(h.idx) {
+            Some(iocb) if iocb.seq == h.seq => iocb as *const Iocb<T, Wb, Rb> as *mut Iocb<T, Wb, Rb>,
+            _ => return Err(io::Error::from_raw_os_error(::libc::EINVAL)),
+        };
+
+        if self.batch.unbatch(iocb) {
+            if self.batch.len() == 0 {
+                self.batch_start = None
+            }
+            return Ok(self.batch.free_iocb(iocb).op)
+        }
+
+        match unsafe { self.ctx.cancel(&mut (*iocb).iocb) } {
+            Err(e) => Err(e),
+            Ok(()) => {
+                self.submitted -= 1;
+                Ok(self.batch.free_iocb(iocb).op)
+            },
         }
     }
 
     /// Queue up a pread operation.
-    pub fn pread<F: AsRawFd>(&mut self, file: &F, mut buf: Rb, off: Offset, tok: T) -> Result<(), (Rb, T)> {
+    pub fn pread<F: AsRawFd>(&mut self, file: &F, buf: Rb, off: Offset, tok: T) -> Result<Iohandle, (Rb, T)> {
+        self.pread_flags(file, buf, off, RwFlags::empty(), tok)
+    }
+
+    /// Queue up a pread operation with per-request `flags`.
+    pub fn pread_flags<F: AsRawFd>(&mut self, file: &F, buf: Rb, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Rb, T)> {
         if self.full() {
             Err((buf, tok))
         } else {
-            let bufptr = buf.rdbuf().as_ptr();
-            let buflen = buf.rdbuf().len();
-            let iocb = Iocb {
-                iocb: aio::Struct_iocb {
-                    aio_buf: bufptr as u64,
-                    aio_count: buflen as u64,
-
-                    .. self.pack_iocb(aio::Iocmd::IO_CMD_PREAD, file, off)
-                },
-                op: IoOp::Pread(buf, tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = aio::Struct_iocb { aio_rw_flags: flags.bits(), .. self.pack_iocb(file, off) };
+            Ok(self.queue(iocb, IoOp::Pread(buf, tok)))
         }
     }
-        
+
     /// Queue up a preadv operation.
-    pub fn preadv<F: AsRawFd>(&mut self, file: &F, mut buf: Vec<Rb>, off: Offset, tok: T) -> Result<(), (Vec<Rb>, T)> {
+    pub fn preadv<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Rb>, off: Offset, tok: T) -> Result<Iohandle, (Vec<Rb>, T)> {
+        self.preadv_flags(file, bufv, off, RwFlags::empty(), tok)
+    }
+
+    /// Queue up a preadv operation with per-request `flags`.
+    pub fn preadv_flags<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Rb>, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Vec<Rb>, T)> {
         if self.full() {
-            Err((buf, tok))
+            Err((bufv, tok))
         } else {
-            let mut iov : Vec<_> = (0..buf.len())
-                .map(|b| aio::Struct_iovec { iov_base: buf[b].rdbuf().as_mut_ptr(),
-                                             iov_len: buf[b].rdbuf().len() as u64 })
-                .collect();
-                
-            let iocb = Iocb {
-                iocb: aio::Struct_iocb {
-                    aio_buf: iov.as_mut_ptr() as u64,
-                    aio_count: iov.len() as u64,
-
-                    .. self.pack_iocb(aio::Iocmd::IO_CMD_PREADV, file, off)
-                },
-                op: IoOp::Preadv(buf, tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = aio::Struct_iocb { aio_rw_flags: flags.bits(), .. self.pack_iocb(file, off) };
+            Ok(self.queue(iocb, IoOp::Preadv(bufv, tok)))
         }
     }
-        
+
     /// Queue up a pwrite operation.
-    pub fn pwrite<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: Offset, tok: T) -> Result<(), (Wb, T)> {
+    pub fn pwrite<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: Offset, tok: T) -> Result<Iohandle, (Wb, T)> {
+        self.pwrite_flags(file, buf, off, RwFlags::empty(), tok)
+    }
+
+    /// Queue up a pwrite operation with per-request `flags`.
+    pub fn pwrite_flags<F: AsRawFd>(&mut self, file: &F, buf: Wb, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Wb, T)> {
         if self.full() {
             Err((buf, tok))
         } else {
-            let bufptr = buf.wrbuf().as_ptr();
-            let buflen = buf.wrbuf().len();
-            let iocb = Iocb {
-                iocb: aio::Struct_iocb {
-                    aio_buf: bufptr as u64,
-                    aio_count: buflen as u64,
-
-                    .. self.pack_iocb(aio::Iocmd::IO_CMD_PWRITE, file, off)
-                },
-                op: IoOp::Pwrite(buf, tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = aio::Struct_iocb { aio_rw_flags: flags.bits(), .. self.pack_iocb(file, off) };
+            Ok(self.queue(iocb, IoOp::Pwrite(buf, tok)))
         }
     }
 
     /// Queue up a pwritev operation.
-    pub fn pwritev<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) -> Result<(), (Vec<Wb>, T)> {
+    pub fn pwritev<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) -> Result<Iohandle, (Vec<Wb>, T)> {
+        self.pwritev_flags(file, bufv, off, RwFlags::empty(), tok)
+    }
+
+    /// Queue up a pwritev operation with per-request `flags`.
+    pub fn pwritev_flags<F: AsRawFd>(&mut self, file: &F, bufv: Vec<Wb>, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Vec<Wb>, T)> {
         if self.full() {
             Err((bufv, tok))
         } else {
-            let iov : Vec<_> = (0..bufv.len())
-                .map(|b| aio::Struct_iovec { iov_base: bufv[b].wrbuf().as_ptr() as *mut u8,
-                                             iov_len: bufv[b].wrbuf().len() as u64 })
-                .collect();
-
-            let iocb = Iocb {
-                iocb: aio::Struct_iocb {
-                    aio_buf: iov.as_ptr() as u64,
-                    aio_count: iov.len() as u64,
-
-                    .. self.pack_iocb(aio::Iocmd::IO_CMD_PWRITEV, file, off)
-                },
-                op: IoOp::Pwritev(bufv, tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = aio::Struct_iocb { aio_rw_flags: flags.bits(), .. self.pack_iocb(file, off) };
+            Ok(self.queue(iocb, IoOp::Pwritev(bufv, tok)))
         }
     }
-        
+
     /// Queue up an fsync operation.
-    pub fn fsync<F: AsRawFd>(&mut self, file: &F, tok: T) -> Result<(), T> {
+    pub fn fsync<F: AsRawFd>(&mut self, file: &F, tok: T) -> Result<Iohandle, T> {
         if self.full() {
             Err(tok)
         } else {
-            let iocb = Iocb {
-                iocb: self.pack_iocb(aio::Iocmd::IO_CMD_FSYNC, file, 0),
-                op: IoOp::Fsync(tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = self.pack_iocb(file, 0);
+            Ok(self.queue(iocb, IoOp::Fsync(tok)))
         }
     }
 
     /// Queue up an fdsync operation.
-    pub fn fdsync<F: AsRawFd>(&mut self, file: &F, tok: T) -> Result<(), T> {
+    pub fn fdsync<F: AsRawFd>(&mut self, file: &F, tok: T) -> Result<Iohandle, T> {
         if self.full() {
             Err(tok)
         } else {
-            let iocb = Iocb {
-                iocb: self.pack_iocb(aio::Iocmd::IO_CMD_FDSYNC, file, 0),
-                op: IoOp::Fdsync(tok),
-            };
-            self.prep_iocb(iocb)
+            let iocb = self.pack_iocb(file, 0);
+            Ok(self.queue(iocb, IoOp::Fdsync(tok)))
+        }
+    }
+
+    /// Queue up a poll operation. This completes when `file` is
+    /// ready for any of `events` (`POLLIN`, `POLLOUT`, etc), so
+    /// sockets, pipes and eventfds can be waited for along with other
+    /// IO. The result is the mask of ready events. Requires Linux
+    /// 4.18 or later.
+    pub fn poll<F: AsRawFd>(&mut self, file: &F, events: c_short, tok: T) -> Result<Iohandle, T> {
+        if self.full() {
+            Err(tok)
+        } else {
+            let iocb = aio::Struct_iocb { aio_buf: events as u16 as u64, .. self.pack_iocb(file, 0) };
+            Ok(self.queue(iocb, IoOp::Poll(tok)))
+        }
+    }
+
+    /// Queue up a user-defined operation on `file`. The operation's
+    /// `prep` method fills in the iocb, and it's returned in an
+    /// `IoOp::Op` once it completes.
+    pub fn op<F: AsRawFd>(&mut self, file: &F, op: Box<dyn Op>, tok: T) -> Result<Iohandle, (Box<dyn Op>, T)> {
+        if self.full() {
+            Err((op, tok))
+        } else {
+            let iocb = self.pack_iocb(file, 0);
+            Ok(self.queue(iocb, IoOp::Op(op, tok)))
         }
     }
 }
 
-impl Drop for Iocontextwrap {
+// The raw pointers are all to iocbs and iovecs which the context
+// owns, so it can be moved to another thread along with everything
+// else it owns.
+unsafe impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send, B: Backend + Send> Send for Iocontext<T, Wb, Rb, B> {}
+
+impl<T: Send, Wb : WrBuf + Send, Rb : RdBuf + Send, B: Backend> Drop for Iocontext<T, Wb, Rb, B> {
     fn drop(&mut self) {
-        let r = unsafe { aio::io_destroy(self.ctx) };
+        let timeout = self.drop_timeout;
 
-        if r < 0 {
-            panic!("io_destroy failed {:?}", io::Error::from_raw_os_error(-r as i32));
+        self.drain(timeout);
+
+        if self.submitted > 0 {
+            // The kernel may still be using these buffers, so leak
+            // them rather than freeing them.
+            mem::forget(mem::replace(&mut self.batch, Iobatch::new(1)))
         }
     }
 }
@@ -375,6 +1310,9 @@ impl<T : Debug, Wb : WrBuf, Rb : RdBuf> Debug for IoOp<T, Wb, Rb> {
             &IoOp::Pwritev(_, ref t) => write!(fmt, "Pwritev {:?}", t),
             &IoOp::Fsync(ref t) => write!(fmt, "Fsync {:?}", t),
             &IoOp::Fdsync(ref t) => write!(fmt, "Fdsync {:?}", t),
+            &IoOp::Poll(ref t) => write!(fmt, "Poll {:?}", t),
+            &IoOp::Op(_, ref t) => write!(fmt, "Op {:?}", t),
+            &IoOp::Group(ref ops, ref t) => write!(fmt, "Group {:?} {:?}", ops.iter().map(|&(ref op, _)| op).collect::<Vec<_>>(), t),
         }
     }
 }
@@ -382,6 +1320,48 @@ impl<T : Debug, Wb : WrBuf, Rb : RdBuf> Debug for IoOp<T, Wb, Rb> {
 struct Iocb<T, Wb : WrBuf, Rb : RdBuf> {
     iocb: aio::Struct_iocb,
     op: IoOp<T, Wb, Rb>,
+    seq: u64,                   // matches Iohandle
+    iov: Option<Iovecs>,        // iovec array for PREADV/PWRITEV
+    group: Option<(usize, usize)>, // group index and member index
+    queued: Option<Instant>,    // when queued, if collecting stats
+    issued: Option<Instant>,    // when submitted, if collecting stats
+}
+
+// Number of iovecs stored inline in an Iocb, to avoid allocating for
+// short vectors.
+const IOV_INLINE: usize = 4;
+
+// Storage for the iovec array of a vectored operation. This lives in
+// the Iocb so that it stays valid until the operation completes.
+enum Iovecs {
+    Inline(usize, [aio::Struct_iovec; IOV_INLINE]),
+    Heap(Vec<aio::Struct_iovec>),
+}
+
+impl Iovecs {
+    fn new<I: ExactSizeIterator<Item=aio::Struct_iovec>>(iter: I) -> Iovecs {
+        if iter.len() <= IOV_INLINE {
+            let mut v = [aio::Struct_iovec { iov_base: ptr::null_mut(), iov_len: 0 }; IOV_INLINE];
+            let mut n = 0;
+
+            for iov in iter {
+                v[n] = iov;
+                n += 1;
+            }
+            Iovecs::Inline(n, v)
+        } else {
+            Iovecs::Heap(iter.collect())
+        }
+    }
+
+    fn as_slice(&self) -> &[aio::Struct_iovec] {
+        match self {
+            &Iovecs::Inline(n, ref v) => &v[..n],
+            &Iovecs::Heap(ref v) => &v[..],
+        }
+    }
+
+    fn len(&self) -> usize { self.as_slice().len() }
 }
 
 struct Iobatch<T, Wb : WrBuf, Rb : RdBuf> {
@@ -399,21 +1379,43 @@ impl<T, Wb : WrBuf, Rb : RdBuf> Iobatch<T, Wb, Rb> {
 
     fn len(&self) -> usize { self.iocbp.len() }
 
-    fn batch<'a>(&'a mut self) -> &'a mut Vec<*mut aio::Struct_iocb> { &mut self.iocbp }
-
-    // Allocate a new Iocb and also add the aio::Struct_iocb onto the current batch
-    fn alloc_iocb(&mut self, init: Iocb<T, Wb, Rb>) -> Result<*mut Iocb<T, Wb, Rb>, Iocb<T, Wb, Rb>> {
+    fn batch(&mut self) -> &mut Vec<*mut aio::Struct_iocb> { &mut self.iocbp }
 
+    // Allocate a new Iocb, without adding it to the batch
+    #[allow(clippy::result_large_err, clippy::type_complexity)]
+    fn alloc(&mut self, init: Iocb<T, Wb, Rb>) -> Result<(usize, *mut Iocb<T, Wb, Rb>), Iocb<T, Wb, Rb>> {
         match self.iocb.allocidx(init) {
             Err(v) => Err(v),
-            Ok(idx) => unsafe {
-                let ptr = as_mut_ptr(Some(&mut self.iocb[idx]));
+            Ok(idx) => Ok((idx, as_mut_ptr(Some(&mut self.iocb[idx])))),
+        }
+    }
+
+    // Allocate a new Iocb and also add the aio::Struct_iocb onto the current batch
+    #[allow(dead_code)]
+    #[allow(clippy::result_large_err, clippy::type_complexity)]
+    fn alloc_iocb(&mut self, init: Iocb<T, Wb, Rb>) -> Result<(usize, *mut Iocb<T, Wb, Rb>), Iocb<T, Wb, Rb>> {
+        match self.alloc(init) {
+            Err(v) => Err(v),
+            Ok((idx, ptr)) => unsafe {
                 self.iocbp.push(as_mut_ptr(Some(&mut (*ptr).iocb)));
-                Ok(ptr)
+                Ok((idx, ptr))
             },
         }
     }
 
+    fn get(&self, idx: usize) -> Option<&Iocb<T, Wb, Rb>> { self.iocb.get(idx) }
+
+    // Remove an entry from the current batch, if it's there. Returns
+    // true if it was.
+    fn unbatch(&mut self, iocb: *mut Iocb<T, Wb, Rb>) -> bool {
+        let p = unsafe { &mut (*iocb).iocb as *mut aio::Struct_iocb };
+
+        match self.iocbp.iter().position(|&b| b == p) {
+            None => false,
+            Some(i) => { self.iocbp.remove(i); true },
+        }
+    }
+
     /// Free an entry. This must not be included in the current iocbp batch.
     fn free_iocb(&mut self, iocb: *mut Iocb<T, Wb, Rb>) -> Iocb<T, Wb, Rb> {
         // XXX assert iocb is not in current self.iocbp?
@@ -423,25 +1425,37 @@ impl<T, Wb : WrBuf, Rb : RdBuf> Iobatch<T, Wb, Rb> {
 
 
 #[cfg(test)]
+#[allow(clippy::char_lit_as_u8,
+        clippy::manual_repeat_n,
+        clippy::unnecessary_cast,
+        clippy::single_match)]
 mod test {
     extern crate std;
     extern crate tempdir;
-    extern crate chrono;
     
-    use super::chrono::duration::Duration;
-    use super::{Iocontext,Iobatch,Iocb,IoOp};
+    use std::time::{Duration, Instant};
+    use super::{Iocontext,Iobatch,Iocb,IoOp,Op,RwFlags,Ioprio,Group,SubmitPolicy};
+    use stats::OpKind;
+    use super::super::FD;
+    use libc::c_short;
+    use std::os::unix::io::AsRawFd;
+    use buf::{RdBuf, WrBuf};
+    use backend::Kernel;
     use super::super::aioabi as aio;
     use std::default::Default;
     use std::cmp::min;
     use std::fs::{File,OpenOptions};
     use std::iter;
+    use std::thread;
+    use std::io;
     use self::tempdir::TempDir;
     
     #[test]
     fn batch_simple() {
         let mut b : Iobatch<usize, Vec<u8>, Vec<u8>> = Iobatch::new(100);
 
-        match b.alloc_iocb(Iocb { iocb: aio::Struct_iocb { .. Default::default() }, op: IoOp::Noop } ) {
+        match b.alloc_iocb(Iocb { iocb: aio::Struct_iocb { .. Default::default() }, op: IoOp::Noop, seq: 0, iov: None, group: None,
+                                  queued: None, issued: None } ) {
             Err(_) => panic!("alloc failed"),
             Ok(_) => (),
         };
@@ -463,11 +1477,17 @@ mod test {
             .open(path).unwrap()
     }
 
+    // Context which is always kernel AIO, for tests which depend on
+    // its ordering, submission errors or cancellation.
+    fn kernel<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send>(maxops: usize) -> io::Result<Iocontext<T, Wb, Rb, Kernel>> {
+        Kernel::new(maxops).map(|k| Iocontext::with_backend(maxops, k))
+    }
+
     #[test]
     fn raw_simple() {
         #[derive(Debug)]
-        enum Op {R, W};
-        let mut io = match Iocontext::new(100) {
+        enum Op {R, W}
+        let mut io = match kernel(100) {
             Err(e) => panic!("iocontext new {:?}", e),
             Ok(io) => io
         };
@@ -503,7 +1523,7 @@ mod test {
                 Ok(n) => assert_eq!(n, io.submitted())
             }
 
-            match io.results(1, 10, Some(Duration::seconds(1))) {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
                 Err(e) => println!("results failed {:?}", e),
                 Ok(res) => for &(ref op, ref r) in res.iter() {
                     match r {
@@ -543,7 +1563,7 @@ mod test {
                 Ok(n) => assert_eq!(n, io.submitted())
             }
 
-            match io.results(1, 10, Some(Duration::seconds(1))) {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
                 Err(e) => println!("results failed {:?}", e),
                 Ok(res) => for &(ref op, ref r) in res.iter() {
                     match r {
@@ -560,6 +1580,488 @@ mod test {
         }
     }
 
+    #[test]
+    fn raw_try_results() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("try");
+
+        match io.try_results(10) {
+            Err(e) => panic!("try_results failed {:?}", e),
+            Ok(res) => assert_eq!(res.len(), 0),
+        }
+
+        for i in 0..5 {
+            let wbuf : Vec<_> = iter::repeat('x' as u8).take(10).collect();
+            assert!(io.pwrite(&file, wbuf, i * 10, i as usize).is_ok());
+        }
+        match io.submit() {
+            Err(e) => panic!("submit failed {:?}", e),
+            Ok(n) => assert_eq!(n, 5),
+        }
+
+        let mut toks = Vec::new();
+        while io.submitted() > 0 {
+            let res = match io.try_results(10) {
+                Err(e) => panic!("try_results failed {:?}", e),
+                Ok(res) => res,
+            };
+            for (op, r) in res.into_iter() {
+                assert_eq!(r.ok(), Some(10));
+                match op {
+                    IoOp::Pwrite(_, tok) => toks.push(tok),
+                    _ => panic!("unexpected {:?}", op),
+                }
+            }
+        }
+        toks.sort();
+        assert_eq!(toks, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn raw_cancel() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("cancel");
+
+        // Batched but unsubmitted ops are just removed
+        let h0 = io.pread(&file, iter::repeat(0).take(10).collect(), 0, 0).ok().unwrap();
+        let h1 = io.pread(&file, iter::repeat(0).take(20).collect(), 0, 1).ok().unwrap();
+        assert!(h0 != h1);
+        assert_eq!(io.batched(), 2);
+
+        match io.cancel(h0) {
+            Ok(IoOp::Pread(buf, 0)) => assert_eq!(buf.len(), 10),
+            r => panic!("unexpected cancel {:?}", r),
+        }
+        assert_eq!(io.batched(), 1);
+
+        // Stale handle, even if the slot is reused
+        let h2 = io.pread(&file, iter::repeat(0).take(30).collect(), 0, 2).ok().unwrap();
+        match io.cancel(h0) {
+            Err(e) => assert_eq!(e.raw_os_error(), Some(::libc::EINVAL)),
+            r => panic!("unexpected cancel {:?}", r),
+        }
+        assert_eq!(io.batched(), 2);
+
+        match io.submit() {
+            Err(e) => panic!("submit failed {:?}", e),
+            Ok(n) => assert_eq!(n, 2),
+        }
+
+        // Regular files don't support cancellation, so the results
+        // must still arrive.
+        assert!(io.cancel(h2).is_err());
+
+        let mut toks = Vec::new();
+        while io.submitted() > 0 {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(res) => for (op, _) in res.into_iter() {
+                    match op {
+                        IoOp::Pread(_, tok) => toks.push(tok),
+                        _ => panic!("unexpected {:?}", op),
+                    }
+                }
+            }
+        }
+        toks.sort();
+        assert_eq!(toks, vec![1, 2]);
+        assert!(io.cancel(h1).is_err());
+    }
+
+    #[test]
+    fn raw_poll() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+        let (rd, wr) = (FD(fds[0]), FD(fds[1]));
+
+        assert!(io.poll(&rd, ::libc::POLLIN, 7).is_ok());
+        match io.submit() {
+            Err(e) => panic!("submit failed {:?}", e),
+            Ok(n) => assert_eq!(n, 1),
+        }
+
+        // Nothing to read yet
+        match io.results(0, 10, Some(Duration::from_millis(10))) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => assert_eq!(res.len(), 0),
+        }
+
+        assert_eq!(unsafe { ::libc::write(wr.as_raw_fd(), "x".as_ptr() as *const ::libc::c_void, 1) }, 1);
+
+        let mut polled = false;
+        while io.submitted() > 0 {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(res) => for (op, r) in res.into_iter() {
+                    match op {
+                        IoOp::Poll(7) => {
+                            let mask = r.ok().unwrap() as c_short;
+                            assert!(mask & ::libc::POLLIN != 0);
+                            polled = true
+                        },
+                        _ => panic!("unexpected {:?}", op),
+                    }
+                }
+            }
+        }
+        assert!(polled);
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]); }
+    }
+
+    #[test]
+    fn raw_rwflags() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("rwflags");
+
+        let flags = RwFlags::DSYNC | RwFlags::SYNC;
+        assert!(flags.contains(RwFlags::DSYNC));
+        assert!(!flags.contains(RwFlags::NOWAIT));
+        assert!(flags.contains(RwFlags::empty()));
+
+        let wbuf : Vec<_> = iter::repeat('x' as u8).take(40).collect();
+        assert!(io.pwrite_flags(&file, wbuf, 0, RwFlags::DSYNC, 0).is_ok());
+        assert!(io.submit().is_ok());
+        match io.results(1, 10, Some(Duration::from_secs(1))) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => {
+                assert_eq!(res.len(), 1);
+                assert_eq!(res[0].1.as_ref().ok(), Some(&40));
+            }
+        }
+
+        // NOWAIT may either succeed or fail fast with EAGAIN
+        let rbuf : Vec<_> = Vec::with_capacity(40);
+        assert!(io.pread_flags(&file, rbuf, 0, RwFlags::NOWAIT, 1).is_ok());
+        assert!(io.submit().is_ok());
+        match io.results(1, 10, Some(Duration::from_secs(1))) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => for &(ref op, ref r) in res.iter() {
+                match r {
+                    &Ok(n) => assert_eq!(n, 40),
+                    &Err(ref e) => assert_eq!(e.raw_os_error(), Some(::libc::EAGAIN)),
+                }
+                match op {
+                    &IoOp::Pread(_, 1) => (),
+                    _ => panic!("unexpected {:?}", op),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn raw_ioprio() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("ioprio");
+
+        assert_eq!(io.ioprio(), None);
+        assert!(io.pwrite(&file, vec!['x' as u8; 10], 0, 0).is_ok());
+
+        io.set_ioprio(Some(Ioprio::Idle));
+        assert!(io.pwrite(&file, vec!['y' as u8; 10], 10, 1).is_ok());
+
+        io.set_ioprio(Some(Ioprio::BestEffort(7)));
+        assert!(io.pread(&file, Vec::with_capacity(20), 0, 2).is_ok());
+
+        let prios : Vec<_> = io.batch.batch().iter()
+            .map(|&iocb| unsafe { ((*iocb).aio_flags & aio::IOCB_FLAG_IOPRIO != 0, (*iocb).aio_reqprio) })
+            .collect();
+        assert_eq!(prios, vec![(false, 0), (true, 3 << 13), (true, (2 << 13) | 7)]);
+
+        assert!(io.submit().is_ok());
+        let mut done = 0;
+        while io.submitted() > 0 {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(res) => for (op, r) in res.into_iter() {
+                    assert!(r.is_ok(), "{:?} failed {:?}", op, r);
+                    done += 1
+                }
+            }
+        }
+        assert_eq!(done, 3);
+    }
+
+    #[test]
+    fn raw_submit_fail() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = match kernel(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("submitfail");
+
+        assert!(io.pwrite(&file, vec!['x' as u8; 10], 0, 0).is_ok());
+        assert!(io.pwrite(&FD(-1), vec!['y' as u8; 10], 0, 1).is_ok());
+        assert!(io.pwrite(&file, vec!['z' as u8; 10], 10, 2).is_ok());
+        assert_eq!(io.batched(), 3);
+
+        // The bad request is rejected, but the rest still get submitted
+        match io.submit() {
+            Err(e) => panic!("submit failed {:?}", e),
+            Ok(n) => assert_eq!(n, 2),
+        }
+        assert_eq!(io.batched(), 0);
+        assert_eq!(io.submitted(), 2);
+        assert_eq!(io.failed(), 1);
+        assert_eq!(io.pending(), 3);
+
+        let mut toks = Vec::new();
+        while io.pending() > 0 {
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(res) => for (op, r) in res.into_iter() {
+                    match op {
+                        IoOp::Pwrite(buf, 1) => {
+                            assert_eq!(buf, vec!['y' as u8; 10]);
+                            assert_eq!(r.err().unwrap().raw_os_error(), Some(::libc::EBADF));
+                            toks.push(1)
+                        },
+                        IoOp::Pwrite(_, tok) => { assert_eq!(r.ok(), Some(10)); toks.push(tok) },
+                        _ => panic!("unexpected {:?}", op),
+                    }
+                }
+            }
+        }
+        toks.sort();
+        assert_eq!(toks, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn raw_shutdown() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = match kernel(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("shutdown");
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // A poll which never becomes ready, a write, and one which
+        // is never submitted
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 0).is_ok());
+        assert!(io.pwrite(&file, vec!['x' as u8; 10], 0, 1).is_ok());
+        assert!(io.submit().is_ok());
+        assert!(io.pwrite(&file, vec!['y' as u8; 10], 0, 2).is_ok());
+
+        let mut res : Vec<_> = io.shutdown(Some(Duration::from_secs(5))).into_iter()
+            .map(|(op, r)| match op {
+                IoOp::Poll(tok) => (tok, r.is_ok()),
+                IoOp::Pwrite(_, tok) => (tok, r.is_ok()),
+                _ => panic!("unexpected {:?}", op),
+            })
+            .collect();
+        res.sort();
+        assert_eq!(res, vec![(0, true), (1, true), (2, false)]);
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]); }
+    }
+
+    #[test]
+    fn raw_drop_inflight() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // This never completes unless it's cancelled
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 0).is_ok());
+        assert!(io.submit().is_ok());
+        assert_eq!(io.submitted(), 1);
+
+        drop(io);
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]); }
+    }
+
+    // Allocate and scribble over a lot of memory of the given size,
+    // to catch anything which has been freed but is still in use.
+    fn scribble(size: usize) {
+        let junk : Vec<Vec<u8>> = (0..256).map(|_| vec![0xa5; size]).collect();
+        drop(junk);
+    }
+
+    #[test]
+    fn raw_iovec_stable() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("iovec");
+
+        // Both inline and allocated iovec arrays
+        for &nbufs in [2, 12].iter() {
+            let wbufs : Vec<Vec<u8>> = (0..nbufs).map(|i| vec![i as u8 + 1; 16]).collect();
+            let expect : Vec<u8> = wbufs.iter().flat_map(|b| b.iter().cloned()).collect();
+
+            assert!(io.pwritev(&file, wbufs, 0, 0).is_ok());
+            scribble(nbufs * 16);
+            assert!(io.submit().is_ok());
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(res) => assert_eq!(res[0].1.as_ref().ok(), Some(&(nbufs * 16))),
+            }
+
+            let rbufs : Vec<Vec<u8>> = (0..nbufs).map(|_| Vec::with_capacity(16)).collect();
+            assert!(io.preadv(&file, rbufs, 0, 1).is_ok());
+            scribble(nbufs * 16);
+            assert!(io.submit().is_ok());
+            match io.results(1, 10, Some(Duration::from_secs(1))) {
+                Err(e) => panic!("results failed {:?}", e),
+                Ok(mut res) => match res.pop() {
+                    Some((IoOp::Preadv(mut bufs, 1), Ok(n))) => {
+                        assert_eq!(n, nbufs * 16);
+                        let got : Vec<u8> = bufs.iter_mut().flat_map(|b| b.rdbuf()[..16].to_vec()).collect();
+                        assert_eq!(got, expect);
+                    },
+                    r => panic!("unexpected {:?}", r),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn raw_deadline() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // Never completes
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 0).is_ok());
+        assert!(io.submit().is_ok());
+
+        // Deadline already passed
+        match io.results_deadline(1, 10, Some(Instant::now())) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => assert_eq!(res.len(), 0),
+        }
+
+        let start = Instant::now();
+        match io.results(1, 10, Some(Duration::from_millis(50))) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => assert_eq!(res.len(), 0),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]); }
+    }
+
+    extern "C" fn nop_handler(_: ::libc::c_int) {}
+
+    #[test]
+    fn raw_deadline_eintr() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 0).is_ok());
+        assert!(io.submit().is_ok());
+
+        // Make sure the signal interrupts rather than restarts
+        unsafe {
+            let mut sa : ::libc::sigaction = ::std::mem::zeroed();
+            sa.sa_sigaction = nop_handler as extern "C" fn(::libc::c_int) as ::libc::sighandler_t;
+            assert_eq!(::libc::sigaction(::libc::SIGUSR1, &sa, ::std::ptr::null_mut()), 0);
+        }
+
+        let me = unsafe { ::libc::pthread_self() };
+        let kicker = thread::spawn(move || {
+            for _ in 0..4 {
+                thread::sleep(Duration::from_millis(50));
+                unsafe { ::libc::pthread_kill(me, ::libc::SIGUSR1) };
+            }
+        });
+
+        // Each signal would add up to 50ms if it restarted the timeout
+        let start = Instant::now();
+        match io.results(1, 10, Some(Duration::from_millis(250))) {
+            Err(e) => panic!("results failed {:?}", e),
+            Ok(res) => assert_eq!(res.len(), 0),
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(250));
+        assert!(elapsed < Duration::from_millis(400), "took {:?}", elapsed);
+
+        kicker.join().unwrap();
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]); }
+    }
+
+    #[test]
+    fn raw_results_reuse() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("reuse");
+        let mut out = Vec::with_capacity(10);
+        let ptr = out.as_ptr();
+
+        for round in 0..3 {
+            for i in 0..5 {
+                assert!(io.pwrite(&file, vec!['x' as u8; 10], i * 10, i as usize).is_ok());
+            }
+            assert!(io.submit().is_ok());
+
+            out.clear();
+            while io.submitted() > 0 {
+                let deadline = Instant::now() + Duration::from_secs(1);
+                match io.results_into(1, 10, Some(deadline), &mut out) {
+                    Err(e) => panic!("results failed {:?}", e),
+                    Ok(n) => assert!(n > 0),
+                }
+            }
+            assert_eq!(out.len(), 5, "round {}", round);
+            assert!(out.iter().all(|&(_, ref r)| r.as_ref().ok() == Some(&10)));
+
+            // Never reallocated
+            assert_eq!(out.as_ptr(), ptr);
+        }
+
+        // Callback draining
+        for i in 0..5 {
+            assert!(io.pread(&file, Vec::with_capacity(10), i * 10, i as usize).is_ok());
+        }
+        assert!(io.submit().is_ok());
+
+        let mut toks = Vec::new();
+        while io.submitted() > 0 {
+            let deadline = Instant::now() + Duration::from_secs(1);
+            let r = io.results_with(1, 10, Some(deadline), |op, res| {
+                assert_eq!(res.ok(), Some(10));
+                match op {
+                    IoOp::Pread(_, tok) => toks.push(tok),
+                    _ => panic!("unexpected {:?}", op),
+                }
+            });
+            assert!(r.is_ok());
+        }
+        toks.sort();
+        assert_eq!(toks, vec![0, 1, 2, 3, 4]);
+    }
+
     #[test]
     fn raw_limit() {
         let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
@@ -581,4 +2083,287 @@ mod test {
             assert_eq!(full, p.is_err());
         }
     }
+
+    // Read which fails if it doesn't fill the buffer
+    struct ReadExact { buf: Vec<u8>, off: u64 }
+
+    impl Op for ReadExact {
+        fn prep(&mut self, iocb: &mut aio::Struct_iocb) {
+            iocb.aio_lio_opcode = aio::Iocmd::IO_CMD_PREAD as u16;
+            iocb.aio_buf = self.buf.as_mut_ptr() as u64;
+            iocb.aio_count = self.buf.len() as u64;
+            iocb.aio_offset = self.off;
+        }
+
+        fn complete(&mut self, res: io::Result<usize>) -> io::Result<usize> {
+            match res {
+                Ok(n) if n < self.buf.len() => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "short read")),
+                res => res,
+            }
+        }
+    }
+
+    #[test]
+    fn raw_op() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("op");
+
+        assert!(io.pwrite(&file, vec!['x' as u8; 30], 0, 0).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        assert_eq!(io.results(1, 1, None).unwrap()[0].1.as_ref().ok(), Some(&30));
+
+        assert!(io.op(&file, Box::new(ReadExact { buf: vec![0; 20], off: 5 }), 1).is_ok());
+        assert!(io.op(&file, Box::new(ReadExact { buf: vec![0; 20], off: 20 }), 2).is_ok());
+        assert_eq!(io.submit().ok(), Some(2));
+
+        let mut got = 0;
+        while io.pending() > 0 {
+            for (op, res) in io.results(1, 2, Some(Duration::from_secs(1))).unwrap() {
+                match op {
+                    IoOp::Op(op, 1) => {
+                        assert_eq!(res.ok(), Some(20));
+                        let op = match op.downcast::<ReadExact>() {
+                            Err(_) => panic!("wrong op type"),
+                            Ok(op) => op,
+                        };
+                        assert_eq!(op.buf, vec!['x' as u8; 20]);
+                    },
+                    IoOp::Op(op, 2) => {
+                        assert_eq!(res.err().map(|e| e.kind()), Some(io::ErrorKind::UnexpectedEof));
+                        assert!(op.downcast::<ReadExact>().is_ok());
+                    },
+                    op => panic!("unexpected {:?}", op),
+                }
+                got += 1;
+            }
+        }
+        assert_eq!(got, 2);
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)]
+    fn raw_group() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(4) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("group");
+        let mut g = Group::new();
+
+        g.pwrite(&file, vec!['a' as u8; 10], 0, 0);
+        g.pwrite(&file, vec!['b' as u8; 10], 10, 1);
+        g.barrier();
+        g.fdsync(&file, 2);
+        g.barrier();
+        g.pread(&file, vec![0; 30], 0, 3);
+        assert_eq!(g.len(), 4);
+
+        // Needs room for every member
+        let h = io.fsync(&file, 99).unwrap();
+        let g = match io.group(g, 100) {
+            Ok(_) => panic!("group should not fit"),
+            Err((g, _)) => g,
+        };
+        assert!(io.cancel(h).is_ok());
+        assert!(io.group(g, 100).is_ok());
+        assert_eq!(io.batched(), 2);
+        assert_eq!(io.pending(), 4);
+        assert!(io.full());
+
+        assert_eq!(io.submit().ok(), Some(2));
+
+        let mut res = Vec::new();
+        while io.pending() > 0 {
+            assert!(io.results_into(1, 4, Some(Instant::now() + Duration::from_secs(1)), &mut res).is_ok());
+        }
+
+        assert_eq!(res.len(), 1);
+        match res.pop().unwrap() {
+            (IoOp::Group(ops, 100), Ok(n)) => {
+                assert_eq!(n, 10 + 10 + 0 + 20);
+                assert_eq!(ops.len(), 4);
+                assert_eq!(ops.iter().map(|&(_, ref r)| *r.as_ref().unwrap()).collect::<Vec<_>>(), vec![10, 10, 0, 20]);
+                match ops[3].0 {
+                    IoOp::Pread(ref buf, 3) => {
+                        assert_eq!(&buf[..10], &['a' as u8; 10][..]);
+                        assert_eq!(&buf[10..20], &['b' as u8; 10][..]);
+                    },
+                    ref op => panic!("unexpected {:?}", op),
+                }
+            },
+            (op, res) => panic!("unexpected {:?} {:?}", op, res),
+        }
+    }
+
+    #[test]
+    fn raw_group_fail() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(5) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("groupfail");
+        let rdonly = FD(-1);
+        let mut g = Group::new();
+
+        g.pwrite(&file, vec!['a' as u8; 10], 0, 0);
+        g.pwrite(&rdonly, vec!['b' as u8; 10], 10, 1);
+        g.barrier();
+        g.fsync(&file, 2);
+
+        assert!(io.group(g, 100).is_ok());
+        let _ = io.submit();
+
+        let mut res = Vec::new();
+        while io.pending() > 0 {
+            assert!(io.results_into(1, 5, Some(Instant::now() + Duration::from_secs(1)), &mut res).is_ok());
+        }
+
+        assert_eq!(res.len(), 1);
+        match res.pop().unwrap() {
+            (IoOp::Group(ops, 100), Err(e)) => {
+                assert_eq!(e.raw_os_error(), Some(::libc::EBADF));
+                assert_eq!(ops[0].1.as_ref().ok(), Some(&10));
+                assert_eq!(ops[1].1.as_ref().err().and_then(|e| e.raw_os_error()), Some(::libc::EBADF));
+                // Never issued
+                assert_eq!(ops[2].1.as_ref().err().and_then(|e| e.raw_os_error()), Some(::libc::ECANCELED));
+            },
+            (op, res) => panic!("unexpected {:?} {:?}", op, res),
+        }
+    }
+
+    #[test]
+    fn raw_submit_policy() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("policy");
+
+        assert_eq!(io.submit_policy(), SubmitPolicy::Manual);
+
+        io.set_submit_policy(SubmitPolicy::Immediate);
+        assert!(io.fsync(&file, 0).is_ok());
+        assert_eq!((io.batched(), io.submitted()), (0, 1));
+
+        io.set_submit_policy(SubmitPolicy::Batch(2));
+        assert!(io.fsync(&file, 1).is_ok());
+        assert_eq!((io.batched(), io.submitted()), (1, 1));
+        assert!(io.fsync(&file, 2).is_ok());
+        assert_eq!((io.batched(), io.submitted()), (0, 3));
+
+        io.set_submit_policy(SubmitPolicy::Latency(Duration::from_millis(20)));
+        assert!(io.fsync(&file, 3).is_ok());
+        assert_eq!(io.batched(), 1);
+        thread::sleep(Duration::from_millis(30));
+        assert!(io.fsync(&file, 4).is_ok());
+        assert_eq!((io.batched(), io.submitted()), (0, 5));
+
+        io.set_submit_policy(SubmitPolicy::OnResults);
+        assert!(io.fsync(&file, 5).is_ok());
+        assert_eq!(io.batched(), 1);
+
+        // Waiting for everything submits the straggler
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut res = Vec::new();
+        while io.pending() > 0 {
+            assert!(io.results_into(io.pending(), 10, Some(deadline), &mut res).is_ok());
+        }
+        assert_eq!(res.len(), 6);
+        assert!(res.iter().all(|&(_, ref r)| r.is_ok()));
+    }
+
+    #[test]
+    fn raw_stats() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = match kernel(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("stats");
+
+        assert!(io.stats().is_none());
+        io.set_stats(true);
+
+        assert!(io.pwrite(&file, vec!['x' as u8; 100], 0, 0).is_ok());
+        assert!(io.pwrite(&file, vec!['x' as u8; 50], 100, 1).is_ok());
+        assert!(io.pread(&file, vec![0; 10], 0, 2).is_ok());
+        assert!(io.pwrite(&FD(-1), vec!['x' as u8; 10], 0, 3).is_ok());
+        thread::sleep(Duration::from_millis(2));
+        assert!(io.submit().is_ok());
+
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut res = Vec::new();
+        while io.pending() > 0 {
+            assert!(io.results_into(io.pending(), 10, Some(deadline), &mut res).is_ok());
+        }
+
+        // Cancelled operations aren't counted
+        let h = io.pwrite(&file, vec!['x' as u8; 10], 0, 4).unwrap();
+        assert!(io.cancel(h).is_ok());
+
+        let stats = io.take_stats().unwrap();
+        let w = stats.op(OpKind::Pwrite);
+        assert_eq!(w.completed, 3);
+        assert_eq!(w.bytes, 150);
+        assert_eq!(w.failed(), 1);
+        assert_eq!(w.errors.get(&::libc::EBADF), Some(&1));
+        assert_eq!(w.latency.count(), 2);
+        assert_eq!(w.queue_time.count(), 3);
+        assert!(w.queue_time.max() >= Duration::from_millis(2));
+
+        let r = stats.op(OpKind::Pread);
+        assert_eq!((r.completed, r.bytes, r.failed()), (1, 10, 0));
+        assert_eq!(stats.ops().len(), 2);
+
+        assert_eq!(io.stats().unwrap().ops().len(), 0);
+        io.set_stats(false);
+        assert!(io.stats().is_none());
+    }
+
+    // Wait for fd to become readable
+    fn readable(fd: ::libc::c_int, ms: ::libc::c_int) -> bool {
+        let mut pfd = ::libc::pollfd { fd: fd, events: ::libc::POLLIN, revents: 0 };
+
+        unsafe { ::libc::poll(&mut pfd, 1, ms) == 1 }
+    }
+
+    #[test]
+    fn raw_eventfd() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = match Iocontext::new(10) {
+            Err(e) => panic!("iocontext new {:?}", e),
+            Ok(io) => io
+        };
+        let file = tmpfile("eventfd");
+        let fd = io.eventfd().unwrap().as_raw_fd();
+
+        assert_eq!(io.eventfd().unwrap().as_raw_fd(), fd);
+        assert!(!readable(fd, 0));
+
+        assert!(io.pwrite(&file, vec!['x' as u8; 10], 0, 0).is_ok());
+        assert!(io.pwrite(&file, vec!['y' as u8; 10], 10, 1).is_ok());
+        assert!(io.submit().is_ok());
+
+        let mut got = 0;
+        while got < 2 {
+            assert!(readable(fd, 1000));
+            assert!(io.eventfd().unwrap().drain().unwrap() > 0);
+            for (_, r) in io.try_results(10).unwrap() {
+                assert_eq!(r.ok(), Some(10));
+                got += 1;
+            }
+        }
+        assert!(!readable(fd, 0));
+        assert_eq!(io.eventfd().unwrap().drain().ok(), Some(0));
+
+        // Rejected requests are signalled too
+        assert!(io.pwrite(&FD(-1), vec!['z' as u8; 10], 0, 2).is_ok());
+        let _ = io.submit();
+        assert!(readable(fd, 1000));
+        assert!(io.eventfd().unwrap().drain().unwrap() > 0);
+        let res = io.try_results(10).unwrap();
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].1.as_ref().err().and_then(|e| e.raw_os_error()), Some(::libc::EBADF));
+    }
 }
@@ -0,0 +1,601 @@
+//! AIO context which can be shared between threads.
+//!
+//! `raw::Iocontext` needs `&mut self` for everything, so sharing one
+//! means putting the whole context behind a lock. This context is
+//! instead split into shards, each a `raw::Iocontext` with its own
+//! backend, lock and part of the `maxops` limit. Each thread queues
+//! operations on a shard of its own where it can, so threads queueing
+//! operations only contend when there are more of them than shards,
+//! and reapers only hold a shard's lock while they collect whatever
+//! has completed on it. `submit` takes every shard's lock in turn, so
+//! it does contend with everything else; a submit policy can be used
+//! instead.
+//!
+//! Results are returned as for `raw::Iocontext`, with the token and
+//! resources each operation was queued with. Any number of threads
+//! can reap; a result is only returned to one of them.
+extern crate std;
+
+use std::io;
+use std::cell::Cell;
+use std::sync::{Mutex, MutexGuard, PoisonError, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::os::unix::io::{AsRawFd, RawFd};
+use libc::c_short;
+
+use buf::{RdBuf, WrBuf};
+use backend::{Backend, Native};
+use raw::{self, Group, IoOp, Op, RwFlags, SubmitPolicy};
+use super::Offset;
+
+// Source of each thread's preferred shard.
+static NEXT_HOME: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static HOME: Cell<Option<usize>> = const { Cell::new(None) });
+
+fn home() -> usize {
+    HOME.with(|h| match h.get() {
+        Some(n) => n,
+        None => {
+            let n = NEXT_HOME.fetch_add(1, Ordering::Relaxed);
+            h.set(Some(n));
+            n
+        },
+    })
+}
+
+// Wait until one of `fds` is readable, or `deadline` passes.
+fn wait(fds: &[RawFd], deadline: Option<Instant>) -> io::Result<()> {
+    let mut pfds: Vec<_> = fds.iter().map(|&fd| ::libc::pollfd { fd, events: ::libc::POLLIN, revents: 0 }).collect();
+    let timeout = match deadline {
+        None => -1,
+        Some(d) => {
+            let now = Instant::now();
+            let left = if d > now { d - now } else { Duration::from_secs(0) };
+            // Round up, so we don't wake just before the deadline
+            let ms = (left.as_secs() * 1000) + (left.subsec_nanos() as u64).div_ceil(1_000_000);
+            std::cmp::min(ms, ::libc::c_int::MAX as u64) as ::libc::c_int
+        },
+    };
+
+    let r = unsafe { ::libc::poll(pfds.as_mut_ptr(), pfds.len() as ::libc::nfds_t, timeout) };
+    if r < 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(::libc::EINTR) {
+            return Err(e)
+        }
+    }
+    Ok(())
+}
+
+// Shard `i`'s part of `maxops`: the first `maxops % shards` shards
+// take one more than the rest.
+fn share(maxops: usize, shards: usize, i: usize) -> usize {
+    maxops / shards + if i < maxops % shards { 1 } else { 0 }
+}
+
+/// Handle for a queued operation, which can be used to `cancel` it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Iohandle {
+    shard: usize,
+    h: raw::Iohandle,
+}
+
+/// Shared AIO context.
+///
+/// All the operations take `&self`, so the context can be shared
+/// between threads, in an `Arc` for example. The type parameters are
+/// as for `raw::Iocontext`.
+///
+/// An operation is only refused once every shard is full. Operations
+/// on different shards are submitted and completed independently, so
+/// there's no ordering between those queued by different threads.
+pub struct Iocontext<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send, B: Backend = Native> {
+    shards: Vec<Mutex<raw::Iocontext<T, Wb, Rb, B>>>,
+    evfds: Vec<RawFd>,          // each shard's eventfd, owned by the shard
+    next_reap: AtomicUsize,     // shard reapers start at, for fairness
+}
+
+impl<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send> Iocontext<T, Wb, Rb> {
+    /// Construct a new Iocontext with up to `maxops` outstanding
+    /// operations split between `shards` native backends. There
+    /// should be about as many shards as threads queueing operations.
+    /// Fails with `EINVAL` unless there's at least one operation per
+    /// shard.
+    pub fn new(maxops: usize, shards: usize) -> io::Result<Iocontext<T, Wb, Rb>> {
+        if shards == 0 || maxops < shards {
+            return Err(io::Error::from_raw_os_error(::libc::EINVAL))
+        }
+        let mut backends = Vec::with_capacity(shards);
+
+        for i in 0..shards {
+            match Native::new(share(maxops, shards, i)) {
+                Err(e) => return Err(e),
+                Ok(b) => backends.push(b),
+            }
+        }
+        Iocontext::with_backends(maxops, backends)
+    }
+}
+
+impl<T: Send, Wb: WrBuf + Send, Rb: RdBuf + Send, B: Backend> Iocontext<T, Wb, Rb, B> {
+    /// Construct a new Iocontext with a shard for each of `backends`,
+    /// sharing `maxops` outstanding operations between them. Fails
+    /// with `EINVAL` unless there's at least one operation per shard.
+    pub fn with_backends(maxops: usize, backends: Vec<B>) -> io::Result<Iocontext<T, Wb, Rb, B>> {
+        let n = backends.len();
+        if n == 0 || maxops < n {
+            return Err(io::Error::from_raw_os_error(::libc::EINVAL))
+        }
+        let mut shards = Vec::with_capacity(n);
+        let mut evfds = Vec::with_capacity(n);
+
+        for (i, b) in backends.into_iter().enumerate() {
+            let mut ctx = raw::Iocontext::with_backend(share(maxops, n, i), b);
+
+            // Created up front, so every operation signals it
+            match ctx.eventfd() {
+                Err(e) => return Err(e),
+                Ok(evfd) => evfds.push(evfd.as_raw_fd()),
+            }
+            shards.push(Mutex::new(ctx));
+        }
+
+        Ok(Iocontext { shards, evfds, next_reap: AtomicUsize::new(0) })
+    }
+
+    /// Number of shards.
+    pub fn shards(&self) -> usize { self.shards.len() }
+
+    /// Maximum number of outstanding operations.
+    pub fn maxops(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.lock(i).maxops()).sum()
+    }
+
+    /// Number of operations which haven't been returned by `results`.
+    pub fn pending(&self) -> usize {
+        (0..self.shards.len()).map(|i| self.lock(i).pending()).sum()
+    }
+
+    /// Submit the batched operations of every shard. Returns the
+    /// number submitted. See `raw::Iocontext::submit`.
+    pub fn submit(&self) -> io::Result<usize> {
+        let mut total = 0;
+        let mut err = None;
+
+        for i in 0..self.shards.len() {
+            match self.lock(i).submit() {
+                Ok(n) => total += n,
+                Err(e) => err = Some(e),
+            }
+        }
+
+        match err {
+            Some(e) if total == 0 => Err(e),
+            _ => Ok(total),
+        }
+    }
+
+    /// Set when each shard submits its batched operations
+    /// automatically. See `raw::Iocontext::set_submit_policy`.
+    pub fn set_submit_policy(&self, policy: SubmitPolicy) {
+        for i in 0..self.shards.len() {
+            self.lock(i).set_submit_policy(policy)
+        }
+    }
+
+    // Lock a shard. A panic while another thread had it locked
+    // doesn't stop the shard being used.
+    fn lock(&self, shard: usize) -> MutexGuard<'_, raw::Iocontext<T, Wb, Rb, B>> {
+        self.shards[shard].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Lock a shard if it's free, or return None.
+    fn try_lock(&self, shard: usize) -> Option<MutexGuard<'_, raw::Iocontext<T, Wb, Rb, B>>> {
+        match self.shards[shard].try_lock() {
+            Ok(ctx) => Some(ctx),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    // Queue an operation with `f`, returning a handle for it.
+    fn queue<R, F>(&self, res: R, f: F) -> Result<Iohandle, R>
+        where F: FnMut(&mut raw::Iocontext<T, Wb, Rb, B>, R) -> Result<raw::Iohandle, R>
+    {
+        self.place(res, f).map(|(shard, h)| Iohandle { shard, h })
+    }
+
+    // Queue something with `f`, on this thread's shard if it's free,
+    // then any other free shard, then waiting for each in turn.
+    // Returns the shard and what `f` returned, or the resources if
+    // every shard is full.
+    fn place<R, H, F>(&self, mut res: R, mut f: F) -> Result<(usize, H), R>
+        where F: FnMut(&mut raw::Iocontext<T, Wb, Rb, B>, R) -> Result<H, R>
+    {
+        let n = self.shards.len();
+        let home = home() % n;
+        let mut busy = false;
+
+        for i in 0..n {
+            let shard = (home + i) % n;
+            let mut ctx = match self.try_lock(shard) {
+                Some(ctx) => ctx,
+                None => { busy = true; continue },
+            };
+
+            match f(&mut ctx, res) {
+                Ok(h) => return Ok((shard, h)),
+                Err(r) => res = r,
+            }
+        }
+
+        if busy {
+            for i in 0..n {
+                let shard = (home + i) % n;
+
+                match f(&mut self.lock(shard), res) {
+                    Ok(h) => return Ok((shard, h)),
+                    Err(r) => res = r,
+                }
+            }
+        }
+        Err(res)
+    }
+
+    /// Return up to `max` IO results, waiting for up to `timeout` (or
+    /// forever if `None`) for at least `min` of them. See
+    /// `raw::Iocontext::results`.
+    #[allow(clippy::type_complexity)]
+    pub fn results(&self, min: usize, max: usize, timeout: Option<Duration>)
+                   -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
+        let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+
+        self.results_deadline(min, max, deadline)
+    }
+
+    /// Return any IO results which are immediately available, without
+    /// blocking.
+    #[allow(clippy::type_complexity)]
+    pub fn try_results(&self, max: usize) -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
+        self.results_deadline(0, max, Some(Instant::now()))
+    }
+
+    /// Return IO results, like `results`, but waiting until an
+    /// absolute `deadline`.
+    ///
+    /// Several threads can wait for results at once. Each shard's
+    /// lock is only held while collecting what has already completed
+    /// on it, so waiting doesn't hold up threads queueing operations.
+    #[allow(clippy::type_complexity)]
+    pub fn results_deadline(&self, min: usize, max: usize, deadline: Option<Instant>)
+                            -> io::Result<Vec<(IoOp<T, Wb, Rb>, io::Result<usize>)>> {
+        let min = std::cmp::min(min, max);
+        let n = self.shards.len();
+        let mut ret = Vec::new();
+        let mut err = None;
+
+        loop {
+            let start = self.next_reap.fetch_add(1, Ordering::Relaxed);
+
+            for i in 0..n {
+                let want = max - ret.len();
+                if want == 0 {
+                    break
+                }
+
+                let mut ctx = self.lock((start + i) % n);
+                let evfd = match ctx.eventfd() {
+                    Err(e) => { err = Some(e); continue },
+                    Ok(evfd) => evfd,
+                };
+
+                // Drain first, so anything which completes while
+                // we're reaping signals again.
+                let _ = evfd.drain();
+                match ctx.results_into(0, want, Some(Instant::now()), &mut ret) {
+                    Err(e) => err = Some(e),
+                    Ok(got) => if got == want {
+                        // There may be more; don't leave other
+                        // reapers waiting for them.
+                        let _ = ctx.eventfd().map(|evfd| evfd.signal(1));
+                    },
+                }
+            }
+
+            if ret.len() >= min || deadline.is_some_and(|d| Instant::now() >= d) {
+                break
+            }
+            if let Err(e) = wait(&self.evfds[..], deadline) {
+                err = Some(e);
+                break
+            }
+        }
+
+        match err {
+            Some(e) if ret.is_empty() => Err(e),
+            _ => Ok(ret),
+        }
+    }
+
+    /// Cancel an outstanding operation. See `raw::Iocontext::cancel`.
+    pub fn cancel(&self, h: Iohandle) -> io::Result<IoOp<T, Wb, Rb>> {
+        if h.shard >= self.shards.len() {
+            return Err(io::Error::from_raw_os_error(::libc::EINVAL))
+        }
+        self.lock(h.shard).cancel(h.h)
+    }
+
+    /// Queue up a pread operation.
+    pub fn pread<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset, tok: T) -> Result<Iohandle, (Rb, T)> {
+        self.queue((buf, tok), |ctx, (buf, tok)| ctx.pread(file, buf, off, tok))
+    }
+
+    /// Queue up a pread operation with per-request `flags`.
+    pub fn pread_flags<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Rb, T)> {
+        self.queue((buf, tok), |ctx, (buf, tok)| ctx.pread_flags(file, buf, off, flags, tok))
+    }
+
+    /// Queue up a preadv operation.
+    pub fn preadv<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset, tok: T) -> Result<Iohandle, (Vec<Rb>, T)> {
+        self.queue((bufv, tok), |ctx, (bufv, tok)| ctx.preadv(file, bufv, off, tok))
+    }
+
+    /// Queue up a preadv operation with per-request `flags`.
+    pub fn preadv_flags<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Vec<Rb>, T)> {
+        self.queue((bufv, tok), |ctx, (bufv, tok)| ctx.preadv_flags(file, bufv, off, flags, tok))
+    }
+
+    /// Queue up a pwrite operation.
+    pub fn pwrite<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset, tok: T) -> Result<Iohandle, (Wb, T)> {
+        self.queue((buf, tok), |ctx, (buf, tok)| ctx.pwrite(file, buf, off, tok))
+    }
+
+    /// Queue up a pwrite operation with per-request `flags`.
+    pub fn pwrite_flags<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Wb, T)> {
+        self.queue((buf, tok), |ctx, (buf, tok)| ctx.pwrite_flags(file, buf, off, flags, tok))
+    }
+
+    /// Queue up a pwritev operation.
+    pub fn pwritev<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset, tok: T) -> Result<Iohandle, (Vec<Wb>, T)> {
+        self.queue((bufv, tok), |ctx, (bufv, tok)| ctx.pwritev(file, bufv, off, tok))
+    }
+
+    /// Queue up a pwritev operation with per-request `flags`.
+    pub fn pwritev_flags<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset, flags: RwFlags, tok: T) -> Result<Iohandle, (Vec<Wb>, T)> {
+        self.queue((bufv, tok), |ctx, (bufv, tok)| ctx.pwritev_flags(file, bufv, off, flags, tok))
+    }
+
+    /// Queue up a fsync operation.
+    pub fn fsync<F: AsRawFd>(&self, file: &F, tok: T) -> Result<Iohandle, T> {
+        self.queue(tok, |ctx, tok| ctx.fsync(file, tok))
+    }
+
+    /// Queue up a fdatasync operation.
+    pub fn fdsync<F: AsRawFd>(&self, file: &F, tok: T) -> Result<Iohandle, T> {
+        self.queue(tok, |ctx, tok| ctx.fdsync(file, tok))
+    }
+
+    /// Queue up a poll operation. See `raw::Iocontext::poll`.
+    pub fn poll<F: AsRawFd>(&self, file: &F, events: c_short, tok: T) -> Result<Iohandle, T> {
+        self.queue(tok, |ctx, tok| ctx.poll(file, events, tok))
+    }
+
+    /// Queue up a group of operations. The whole group goes on one
+    /// shard. See `raw::Iocontext::group`.
+    pub fn group(&self, group: Group<T, Wb, Rb>, tok: T) -> Result<(), (Group<T, Wb, Rb>, T)> {
+        self.place((group, tok), |ctx, (group, tok)| ctx.group(group, tok)).map(|_| ())
+    }
+
+    /// Queue up a custom operation. See `raw::Iocontext::op`.
+    pub fn op<F: AsRawFd>(&self, file: &F, op: Box<dyn Op>, tok: T) -> Result<Iohandle, (Box<dyn Op>, T)> {
+        self.queue((op, tok), |ctx, (op, tok)| ctx.op(file, op, tok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use raw::{Group, IoOp, RwFlags};
+    use super::super::FD;
+    use super::Iocontext;
+
+    fn tmpfile(name: &str) -> File {
+        let tmp = TempDir::new("test").unwrap();
+        let mut path = tmp.into_path();
+
+        path.push(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    fn is_sync<S: Sync + Send>(_: &S) {}
+
+    #[test]
+    fn simple() {
+        let io : Iocontext<u32, Vec<u8>, Vec<u8>> = Iocontext::new(10, 2).unwrap();
+        let file = tmpfile("simple");
+
+        is_sync(&io);
+        assert_eq!(io.shards(), 2);
+        assert_eq!(io.maxops(), 10);
+
+        assert!(io.pwrite(&file, vec![b'x'; 40], 0, 1).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        let res = io.results(1, 10, None).unwrap();
+        assert_eq!(res.len(), 1);
+        match res[0] {
+            (IoOp::Pwrite(_, 1), Ok(40)) => (),
+            ref r => panic!("bad result {:?}", r),
+        }
+
+        assert!(io.pread(&file, vec![0; 20], 10, 2).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        // min > max waits for max
+        match io.results(2, 1, None).unwrap().pop() {
+            Some((IoOp::Pread(buf, 2), Ok(20))) => assert_eq!(buf, vec![b'x'; 20]),
+            r => panic!("bad result {:?}", r),
+        }
+        assert_eq!(io.pending(), 0);
+        assert_eq!(io.try_results(10).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn split() {
+        let io : Iocontext<u32, Vec<u8>, Vec<u8>> = Iocontext::new(10, 3).unwrap();
+        let file = tmpfile("split");
+
+        assert_eq!(io.maxops(), 10);
+        assert_eq!((0..3).map(|i| io.lock(i).maxops()).collect::<Vec<_>>(), vec![4, 3, 3]);
+        for i in 0..10 {
+            assert!(io.fsync(&file, i).is_ok());
+        }
+        assert!(io.fsync(&file, 10).is_err());
+
+        let few = Iocontext::<u32, Vec<u8>, Vec<u8>>::new(2, 3);
+        assert_eq!(few.err().and_then(|e| e.raw_os_error()), Some(::libc::EINVAL));
+    }
+
+    #[test]
+    fn poisoned() {
+        let io : Arc<Iocontext<u32, Vec<u8>, Vec<u8>>> = Arc::new(Iocontext::new(4, 1).unwrap());
+        let file = tmpfile("poisoned");
+
+        let p = io.clone();
+        assert!(thread::spawn(move || { let _ctx = p.shards[0].lock(); panic!("poison") }).join().is_err());
+        assert!(io.shards[0].is_poisoned());
+
+        assert!(io.fsync(&file, 1).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        assert_eq!(io.results(1, 10, Some(Duration::from_secs(10))).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn group_poll() {
+        let io : Iocontext<u32, Vec<u8>, Vec<u8>> = Iocontext::new(8, 2).unwrap();
+        let file = tmpfile("grouppoll");
+        let mut fds = [0; 2];
+        let mut g = Group::new();
+
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+        assert_eq!(unsafe { ::libc::write(fds[1], b"x".as_ptr() as *const _, 1) }, 1);
+
+        g.pwritev_flags(&file, vec![vec![b'a'; 10], vec![b'b'; 10]], 0, RwFlags::empty(), 0);
+        g.barrier();
+        g.pread(&file, vec![0; 20], 0, 1);
+        assert!(io.group(g, 10).is_ok());
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 11).is_ok());
+        assert!(io.submit().is_ok());
+
+        let mut res = Vec::new();
+        while io.pending() > 0 {
+            res.extend(io.results(1, 10, Some(Duration::from_secs(10))).unwrap());
+        }
+        res.sort_by_key(|(op, _)| match *op { IoOp::Group(_, t) | IoOp::Poll(t) => t, _ => 0 });
+        match res[..] {
+            [(IoOp::Group(ref ops, 10), Ok(40)), (IoOp::Poll(11), Ok(ev))] => {
+                assert_eq!(ops.len(), 2);
+                assert!(ev & ::libc::POLLIN as usize != 0);
+            },
+            ref r => panic!("bad results {:?}", r),
+        }
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]) };
+    }
+
+    #[test]
+    fn full() {
+        let io : Iocontext<u32, Vec<u8>, Vec<u8>> = Iocontext::new(4, 2).unwrap();
+        let file = tmpfile("full");
+
+        for i in 0..4 {
+            assert!(io.pwrite(&file, vec![i as u8; 10], i * 10, i as u32).is_ok());
+        }
+        match io.pwrite(&file, vec![9; 10], 40, 9) {
+            Err((buf, tok)) => { assert_eq!(buf, vec![9; 10]); assert_eq!(tok, 9) },
+            Ok(_) => panic!("queued past maxops"),
+        }
+        assert_eq!(io.pending(), 4);
+    }
+
+    #[test]
+    fn cancel() {
+        let io : Iocontext<u32, Vec<u8>, Vec<u8>> = Iocontext::new(4, 2).unwrap();
+        let file = tmpfile("cancel");
+
+        let h = io.pread(&file, vec![0; 10], 0, 7).ok().unwrap();
+        match io.cancel(h) {
+            Ok(IoOp::Pread(buf, 7)) => assert_eq!(buf.len(), 10),
+            r => panic!("bad cancel {:?}", r),
+        }
+        assert_eq!(io.pending(), 0);
+        assert!(io.cancel(h).is_err());
+    }
+
+    #[test]
+    fn threads() {
+        const THREADS: usize = 4;
+        const OPS: usize = 50;
+
+        type Ctx = Iocontext<(usize, usize), Vec<u8>, Vec<u8>>;
+
+        let io : Arc<Ctx> = Arc::new(Iocontext::new(32, THREADS).unwrap());
+        let file = Arc::new(tmpfile("threads"));
+
+        let reaper = {
+            let io = io.clone();
+            thread::spawn(move || {
+                let mut seen = vec![vec![false; OPS]; THREADS];
+                let mut n = 0;
+
+                while n < THREADS * OPS {
+                    for (op, res) in io.results(1, 16, Some(Duration::from_secs(10))).unwrap() {
+                        match op {
+                            IoOp::Pwrite(buf, (t, i)) => {
+                                assert_eq!(res.ok(), Some(10));
+                                assert_eq!(buf, vec![t as u8; 10]);
+                                assert!(!seen[t][i]);
+                                seen[t][i] = true;
+                            },
+                            op => panic!("bad op {:?}", op),
+                        }
+                        n += 1;
+                    }
+                }
+            })
+        };
+
+        let writers: Vec<_> = (0..THREADS).map(|t| {
+            let io = io.clone();
+            let file = file.clone();
+            thread::spawn(move || {
+                for i in 0..OPS {
+                    let mut r = io.pwrite(&*file, vec![t as u8; 10], ((t * OPS + i) * 10) as u64, (t, i));
+                    // Wait for the reaper to make room
+                    while let Err((buf, tok)) = r {
+                        thread::yield_now();
+                        r = io.pwrite(&*file, buf, ((t * OPS + i) * 10) as u64, tok);
+                    }
+                    let _ = io.submit();
+                }
+            })
+        }).collect();
+
+        for w in writers {
+            w.join().unwrap();
+        }
+        reaper.join().unwrap();
+        assert_eq!(io.pending(), 0);
+    }
+}
@@ -0,0 +1,195 @@
+//! Operation statistics.
+//!
+//! A context can keep counts of the operations it completes, broken
+//! down by kind, along with histograms of how long they were batched
+//! before submission and how long after submission they were reaped.
+//! Operations which are cancelled outright, by `cancel` or by the
+//! context shutting down, aren't counted. See
+//! `raw::Iocontext::set_stats`.
+extern crate std;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Kind of operation, for breaking down statistics.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum OpKind {
+    Noop,
+    Pread,
+    Preadv,
+    Pwrite,
+    Pwritev,
+    Fsync,
+    Fdsync,
+    Poll,
+    /// User-defined operation.
+    Op,
+}
+
+const NKINDS: usize = 9;
+
+const BUCKETS: usize = 64;
+
+/// Histogram of durations, with power-of-two buckets. Bucket `i`
+/// counts durations of `2^i` to `2^(i+1)-1` nanoseconds, except that
+/// bucket 0 also counts 0.
+#[derive(Clone)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    sum: u64,                   // ns
+    max: u64,                   // ns
+}
+
+pub(crate) fn nanos(d: Duration) -> u64 {
+    d.as_secs().saturating_mul(1_000_000_000).saturating_add(d.subsec_nanos() as u64)
+}
+
+impl Histogram {
+    /// Create an empty histogram.
+    pub fn new() -> Histogram {
+        Histogram { buckets: [0; BUCKETS], count: 0, sum: 0, max: 0 }
+    }
+
+    /// Add a sample.
+    pub fn record(&mut self, d: Duration) {
+        let ns = nanos(d);
+        let b = if ns == 0 { 0 } else { 63 - ns.leading_zeros() as usize };
+
+        self.buckets[b] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(ns);
+        if ns > self.max {
+            self.max = ns
+        }
+    }
+
+    /// Number of samples.
+    pub fn count(&self) -> u64 { self.count }
+
+    /// Mean of the samples, or zero if there are none.
+    pub fn mean(&self) -> Duration {
+        Duration::from_nanos(self.sum.checked_div(self.count).unwrap_or(0))
+    }
+
+    /// Largest sample.
+    pub fn max(&self) -> Duration { Duration::from_nanos(self.max) }
+
+    /// Upper bound of the duration below which fraction `q` (0.0 to
+    /// 1.0) of the samples fall, so `quantile(0.99)` is the 99th
+    /// percentile. This is only as precise as the buckets, but never
+    /// more than the largest sample.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let want = (q * self.count as f64).ceil() as u64;
+        let mut seen = 0;
+
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if n > 0 && seen >= want {
+                let top = if i == BUCKETS - 1 { u64::MAX } else { (1 << (i + 1)) - 1 };
+                return Duration::from_nanos(std::cmp::min(top, self.max))
+            }
+        }
+        self.max()
+    }
+
+    /// Counts for each bucket.
+    pub fn buckets(&self) -> &[u64] { &self.buckets[..] }
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram { Histogram::new() }
+}
+
+/// Statistics for one kind of operation.
+#[derive(Clone, Default)]
+pub struct OpStats {
+    /// Operations completed, successfully or not.
+    pub completed: u64,
+    /// Bytes transferred by reads and writes.
+    pub bytes: u64,
+    /// Number of failed operations for each errno. Errors without an
+    /// errno are counted under 0.
+    pub errors: BTreeMap<i32, u64>,
+    /// Time from being queued to being submitted, or to being
+    /// rejected when it was submitted.
+    pub queue_time: Histogram,
+    /// Time from submission until the completion was reaped. This
+    /// includes however long the completion waited to be collected
+    /// by `results` or the like, not just the time the kernel took.
+    pub latency: Histogram,
+}
+
+impl OpStats {
+    /// Total number of failed operations.
+    pub fn failed(&self) -> u64 { self.errors.values().sum() }
+}
+
+/// Statistics for a context.
+#[derive(Clone, Default)]
+pub struct Stats {
+    kinds: [OpStats; NKINDS],
+}
+
+impl Stats {
+    /// Create empty statistics.
+    pub fn new() -> Stats { Default::default() }
+
+    /// Statistics for one kind of operation.
+    pub fn op(&self, kind: OpKind) -> &OpStats { &self.kinds[kind as usize] }
+
+    /// Mutable statistics for one kind of operation.
+    pub fn op_mut(&mut self, kind: OpKind) -> &mut OpStats { &mut self.kinds[kind as usize] }
+
+    /// Statistics for the kinds of operation which have been used.
+    pub fn ops(&self) -> Vec<(OpKind, &OpStats)> {
+        let kinds = [OpKind::Noop, OpKind::Pread, OpKind::Preadv, OpKind::Pwrite, OpKind::Pwritev,
+                     OpKind::Fsync, OpKind::Fdsync, OpKind::Poll, OpKind::Op];
+
+        kinds.iter().map(|&k| (k, self.op(k))).filter(|&(_, s)| s.completed > 0).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{Histogram, Stats, OpKind};
+
+    #[test]
+    fn histogram() {
+        let mut h = Histogram::new();
+
+        assert_eq!(h.count(), 0);
+        assert_eq!(h.mean(), Duration::from_secs(0));
+
+        for us in 1..101 {
+            h.record(Duration::from_micros(us));
+        }
+        h.record(Duration::from_secs(0));
+
+        assert_eq!(h.count(), 101);
+        assert_eq!(h.max(), Duration::from_micros(100));
+        assert_eq!(h.buckets()[0], 1);
+        assert_eq!(h.buckets().iter().sum::<u64>(), 101);
+
+        // 1000ns is in [512, 1023]
+        assert_eq!(h.buckets()[9], 1);
+
+        let p50 = h.quantile(0.5);
+        assert!(p50 >= Duration::from_micros(50) && p50 < Duration::from_micros(100), "p50 {:?}", p50);
+        assert_eq!(h.quantile(1.0), Duration::from_micros(100));
+    }
+
+    #[test]
+    fn ops() {
+        let mut s = Stats::new();
+
+        assert!(s.ops().is_empty());
+        s.op_mut(OpKind::Pwrite).completed += 1;
+        *s.op_mut(OpKind::Pwrite).errors.entry(5).or_insert(0) += 2;
+
+        assert_eq!(s.ops().len(), 1);
+        assert_eq!(s.ops()[0].0, OpKind::Pwrite);
+        assert_eq!(s.op(OpKind::Pwrite).failed(), 2);
+    }
+}
@@ -0,0 +1,584 @@
+//! Thread pool backends.
+//!
+//! Kernel AIO is only really asynchronous for `O_DIRECT` IO on some
+//! filesystems; otherwise `io_submit` quietly blocks until the IO is
+//! done. `Threadpool` performs operations with ordinary blocking
+//! syscalls on a set of worker threads instead, and `Auto` chooses
+//! between it and kernel AIO for each operation, depending on whether
+//! its file was opened `O_DIRECT`.
+//!
+//! Both deliver completions through `raw::Iocontext` as usual, and
+//! signal the context's eventfd, if it has one. The pool signals its
+//! own duplicate of it, since a worker may still be running an
+//! operation after the context has gone.
+extern crate std;
+
+use std::io;
+use std::collections::{HashSet, VecDeque};
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::{Duration, Instant};
+use libc::{c_int, c_long, off_t};
+
+use aioabi as aio;
+use backend::{Backend, Kernel};
+use eventfd::EventFd;
+
+// Pointer to an iocb being handed to a worker.
+struct Job(*mut aio::Struct_iocb);
+
+// The iocb stays valid, and isn't otherwise touched, until it
+// completes.
+unsafe impl Send for Job {}
+
+struct State {
+    queue: VecDeque<Job>,                       // waiting for a worker
+    done: VecDeque<aio::Struct_io_event>,       // completed
+    running: usize,                             // being performed
+    shutdown: bool,
+    evfd: Option<Arc<EventFd>>,                 // the context's, duplicated
+}
+
+struct Shared {
+    state: Mutex<State>,
+    work: Condvar,              // signalled when jobs are queued
+    done: Condvar,              // signalled when jobs complete
+}
+
+/// Backend which performs operations on a pool of worker threads.
+pub struct Threadpool {
+    shared: Arc<Shared>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Threadpool {
+    /// Start a pool of `threads` workers. This is the maximum number
+    /// of operations which can be performed at once, so it must be at
+    /// least one.
+    pub fn new(threads: usize) -> io::Result<Threadpool> {
+        if threads == 0 {
+            return Err(io::Error::from_raw_os_error(::libc::EINVAL))
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { queue: VecDeque::new(), done: VecDeque::new(), running: 0, shutdown: false,
+                                      evfd: None }),
+            work: Condvar::new(),
+            done: Condvar::new(),
+        });
+        let mut pool = Threadpool { shared, workers: Vec::with_capacity(threads) };
+
+        for i in 0..threads {
+            let shared = pool.shared.clone();
+
+            // If this fails, dropping the pool stops the others
+            match thread::Builder::new().name(format!("aio-worker-{}", i)).spawn(move || worker(shared)) {
+                Err(e) => return Err(e),
+                Ok(h) => pool.workers.push(h),
+            }
+        }
+
+        Ok(pool)
+    }
+
+    /// Number of operations queued or being performed which haven't
+    /// been reaped.
+    pub fn inflight(&self) -> usize {
+        let st = self.shared.state.lock().unwrap();
+
+        st.queue.len() + st.running + st.done.len()
+    }
+}
+
+fn worker(shared: Arc<Shared>) {
+    loop {
+        let job = {
+            let mut st = shared.state.lock().unwrap();
+
+            loop {
+                if let Some(job) = st.queue.pop_front() {
+                    st.running += 1;
+                    break job
+                }
+                if st.shutdown {
+                    return
+                }
+                st = shared.work.wait(st).unwrap();
+            }
+        };
+
+        let iocb = unsafe { &*job.0 };
+        let ev = aio::Struct_io_event { data: iocb.data, obj: job.0 as u64, res: unsafe { perform(iocb) }, res2: 0 };
+        let resfd = iocb.aio_flags & aio::IOCB_FLAG_RESFD != 0;
+
+        // The iocb may be gone once it's been reaped, so don't touch it
+        let evfd = {
+            let mut st = shared.state.lock().unwrap();
+
+            st.done.push_back(ev);
+            st.running -= 1;
+            if resfd { st.evfd.clone() } else { None }
+        };
+        shared.done.notify_all();
+
+        if let Some(evfd) = evfd {
+            let _ = evfd.signal(1);
+        }
+    }
+}
+
+fn errno() -> i64 { io::Error::last_os_error().raw_os_error().unwrap_or(::libc::EIO) as i64 }
+
+// Perform an operation with blocking syscalls, returning the result
+// or -errno.
+unsafe fn perform(iocb: &aio::Struct_iocb) -> i64 {
+    let fd = iocb.aio_fildes as c_int;
+    let off = iocb.aio_offset as off_t;
+    let op = iocb.aio_lio_opcode;
+
+    // Single buffer as an iovec, for preadv2/pwritev2
+    let single = aio::Struct_iovec { iov_base: iocb.aio_buf as *mut u8, iov_len: iocb.aio_count as ::libc::size_t };
+    let (iov, iovcnt) = if op == aio::Iocmd::IO_CMD_PREADV as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16 {
+        (iocb.aio_buf as *const aio::Struct_iovec, iocb.aio_count as c_int)
+    } else {
+        (&single as *const aio::Struct_iovec, 1)
+    };
+
+    let r = if op == aio::Iocmd::IO_CMD_PREAD as u16 || op == aio::Iocmd::IO_CMD_PREADV as u16 {
+        if iocb.aio_rw_flags != 0 {
+            rw2(::libc::SYS_preadv2, fd, iov, iovcnt, iocb.aio_offset, iocb.aio_rw_flags)
+        } else {
+            ::libc::preadv(fd, iov as *const ::libc::iovec, iovcnt, off) as i64
+        }
+    } else if op == aio::Iocmd::IO_CMD_PWRITE as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16 {
+        if iocb.aio_rw_flags != 0 {
+            rw2(::libc::SYS_pwritev2, fd, iov, iovcnt, iocb.aio_offset, iocb.aio_rw_flags)
+        } else {
+            ::libc::pwritev(fd, iov as *const ::libc::iovec, iovcnt, off) as i64
+        }
+    } else if op == aio::Iocmd::IO_CMD_FSYNC as u16 {
+        ::libc::fsync(fd) as i64
+    } else if op == aio::Iocmd::IO_CMD_FDSYNC as u16 {
+        ::libc::fdatasync(fd) as i64
+    } else if op == aio::Iocmd::IO_CMD_POLL as u16 {
+        let mut pfd = ::libc::pollfd { fd, events: iocb.aio_buf as i16, revents: 0 };
+
+        loop {
+            match ::libc::poll(&mut pfd, 1, -1) {
+                r if r < 0 && errno() == ::libc::EINTR as i64 => continue,
+                r if r < 0 => break r as i64,
+                _ => break pfd.revents as u16 as i64,
+            }
+        }
+    } else {
+        return -::libc::EINVAL as i64
+    };
+
+    if r < 0 { -errno() } else { r }
+}
+
+// preadv2/pwritev2, for per-request RWF_* flags. The offset is split
+// in two for 32-bit kernels; 64-bit ones ignore the high half.
+unsafe fn rw2(nr: c_long, fd: c_int, iov: *const aio::Struct_iovec, iovcnt: c_int, off: u64, flags: u32) -> i64 {
+    ::libc::syscall(nr, fd, iov, iovcnt, off as c_long, (off >> 32) as c_long, flags as c_int) as i64
+}
+
+impl Backend for Threadpool {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        {
+            let mut st = self.shared.state.lock().unwrap();
+
+            st.queue.extend(iocbs.iter().map(|&p| Job(p)));
+        }
+        self.shared.work.notify_all();
+
+        Ok(iocbs.len())
+    }
+
+    // Only operations which haven't been started can be cancelled.
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        let mut st = self.shared.state.lock().unwrap();
+
+        match st.queue.iter().position(|j| j.0 == iocb) {
+            Some(i) => { st.queue.remove(i); Ok(()) },
+            None if st.done.iter().any(|ev| ev.obj == iocb as u64) =>
+                Err(io::Error::from_raw_os_error(::libc::EINVAL)),
+            None => Err(io::Error::from_raw_os_error(::libc::EAGAIN)),
+        }
+    }
+
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        let mut st = self.shared.state.lock().unwrap();
+        let mut n = 0;
+
+        loop {
+            while n < events.len() {
+                match st.done.pop_front() {
+                    None => break,
+                    Some(ev) => { events[n] = ev; n += 1 },
+                }
+            }
+            if n >= min || n == events.len() {
+                return Ok(n)
+            }
+
+            st = match deadline {
+                None => self.shared.done.wait(st).unwrap(),
+                Some(d) => {
+                    let now = Instant::now();
+
+                    if d <= now {
+                        return Ok(n)
+                    }
+                    self.shared.done.wait_timeout(st, d - now).unwrap().0
+                },
+            };
+        }
+    }
+
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        self.getevents(0, events, Some(Instant::now())).ok()
+    }
+
+    fn set_eventfd(&mut self, fd: RawFd) -> io::Result<()> {
+        let evfd = EventFd::dup(fd)?;
+
+        self.shared.state.lock().unwrap().evfd = Some(Arc::new(evfd));
+        Ok(())
+    }
+}
+
+impl Drop for Threadpool {
+    fn drop(&mut self) {
+        let busy = {
+            let mut st = self.shared.state.lock().unwrap();
+
+            st.shutdown = true;
+            st.running > 0
+        };
+        self.shared.work.notify_all();
+
+        // Operations which couldn't be drained may block a worker
+        // indefinitely, so leave them to exit by themselves.
+        if !busy {
+            for w in self.workers.drain(..) {
+                let _ = w.join();
+            }
+        }
+    }
+}
+
+/// Backend which uses kernel AIO for files opened `O_DIRECT`, and a
+/// thread pool for everything else. Polls always use kernel AIO.
+///
+/// While operations are outstanding on both, waiting for completions
+/// checks the kernel at least every millisecond.
+pub struct Auto {
+    kernel: Kernel,
+    pool: Threadpool,
+    inkernel: usize,            // outstanding kernel operations
+    inpool: HashSet<u64>,       // iocbs of outstanding pool operations
+}
+
+impl Auto {
+    /// Set up kernel AIO for up to `maxops` operations, and a pool of
+    /// `threads` workers.
+    pub fn new(maxops: usize, threads: usize) -> io::Result<Auto> {
+        let kernel = Kernel::new(maxops)?;
+        let pool = Threadpool::new(threads)?;
+
+        Ok(Auto { kernel, pool, inkernel: 0, inpool: HashSet::new() })
+    }
+
+    /// Number of outstanding operations using kernel AIO and the
+    /// thread pool respectively.
+    pub fn inflight(&self) -> (usize, usize) { (self.inkernel, self.inpool.len()) }
+
+    // Whether an operation should use kernel AIO. Bad file
+    // descriptors go to the kernel so it can report them.
+    unsafe fn use_kernel(&self, iocb: *const aio::Struct_iocb) -> bool {
+        if (*iocb).aio_lio_opcode == aio::Iocmd::IO_CMD_POLL as u16 {
+            return true
+        }
+
+        let fl = ::libc::fcntl((*iocb).aio_fildes as c_int, ::libc::F_GETFL);
+        fl < 0 || fl & ::libc::O_DIRECT != 0
+    }
+
+    // Forget pool operations which have completed.
+    fn pool_done(&mut self, events: &[aio::Struct_io_event]) {
+        for ev in events {
+            self.inpool.remove(&ev.obj);
+        }
+    }
+}
+
+impl Backend for Auto {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        let mut total = 0;
+
+        while total < iocbs.len() {
+            // Submit the next run of operations going the same way
+            let kernel = self.use_kernel(iocbs[total]);
+            let run = iocbs[total..].iter().position(|&p| self.use_kernel(p) != kernel)
+                .unwrap_or(iocbs.len() - total);
+            let batch = &iocbs[total..total + run];
+
+            let r = if kernel { self.kernel.submit(batch) } else { self.pool.submit(batch) };
+
+            match r {
+                Err(e) => if total == 0 { return Err(e) } else { break },
+                Ok(n) => {
+                    if kernel {
+                        self.inkernel += n
+                    } else {
+                        self.inpool.extend(batch[..n].iter().map(|&p| p as u64))
+                    }
+                    total += n;
+                    if n < run {
+                        break
+                    }
+                },
+            }
+        }
+
+        Ok(total)
+    }
+
+    // Goes wherever the operation was submitted, even if its file has
+    // changed since.
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        let kernel = !self.inpool.contains(&(iocb as u64));
+        let r = if kernel { self.kernel.cancel(iocb) } else { self.pool.cancel(iocb) };
+
+        if r.is_ok() {
+            if kernel { self.inkernel -= 1 } else { self.inpool.remove(&(iocb as u64)); }
+        }
+        r
+    }
+
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        if self.inpool.is_empty() {
+            let r = self.kernel.getevents(min, events, deadline);
+            if let Ok(n) = r { self.inkernel -= n }
+            return r
+        }
+        if self.inkernel == 0 {
+            let r = self.pool.getevents(min, events, deadline);
+            if let Ok(n) = r { self.pool_done(&events[..n]) }
+            return r
+        }
+
+        // Outstanding on both, so alternate between them
+        let slice = Duration::from_millis(1);
+        let mut n = 0;
+
+        loop {
+            let now = Instant::now();
+            let k = match self.kernel.getevents(0, &mut events[n..], Some(now)) {
+                Err(e) => if n == 0 { return Err(e) } else { return Ok(n) },
+                Ok(k) => k,
+            };
+            self.inkernel -= k;
+            n += k;
+
+            let wait = match deadline {
+                Some(d) if d <= now + slice => d,
+                _ => now + slice,
+            };
+            let p = match self.pool.getevents(min.saturating_sub(n), &mut events[n..], Some(wait)) {
+                Err(e) => if n == 0 { return Err(e) } else { return Ok(n) },
+                Ok(p) => p,
+            };
+            self.pool_done(&events[n..n + p]);
+            n += p;
+
+            if n >= min || n == events.len() || deadline.is_some_and(|d| Instant::now() >= d) {
+                return Ok(n)
+            }
+            if self.inpool.is_empty() || self.inkernel == 0 {
+                // Only one left to wait for
+                return match self.getevents(min - n, &mut events[n..], deadline) {
+                    Err(e) => if n == 0 { Err(e) } else { Ok(n) },
+                    Ok(m) => Ok(n + m),
+                }
+            }
+        }
+    }
+
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        let now = Instant::now();
+        let k = self.kernel.reap(events).or_else(|| self.kernel.getevents(0, events, Some(now)).ok()).unwrap_or(0);
+        let p = self.pool.reap(&mut events[k..]).unwrap_or(0);
+
+        self.inkernel -= k;
+        self.pool_done(&events[k..k + p]);
+        Some(k + p)
+    }
+
+    fn set_eventfd(&mut self, fd: RawFd) -> io::Result<()> {
+        self.kernel.set_eventfd(fd)?;
+        self.pool.set_eventfd(fd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+    use raw::{Iocontext, IoOp};
+    use super::super::FD;
+    use super::{Threadpool, Auto};
+
+    fn tmpfile(name: &str) -> File {
+        let tmp = TempDir::new("test").unwrap();
+        let mut path = tmp.into_path();
+
+        path.push(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    #[test]
+    fn threadpool() {
+        assert_eq!(Threadpool::new(0).err().and_then(|e| e.raw_os_error()), Some(::libc::EINVAL));
+
+        let pool = Threadpool::new(2).unwrap();
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, pool);
+        let file = tmpfile("pool");
+
+        assert!(io.pwrite(&file, vec![b'x'; 100], 0, 0).is_ok());
+        assert!(io.fdsync(&file, 1).is_ok());
+        assert_eq!(io.submit().ok(), Some(2));
+
+        let res = io.results(2, 10, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(res.len(), 2);
+        for (op, res) in res {
+            match op {
+                IoOp::Pwrite(_, 0) => assert_eq!(res.ok(), Some(100)),
+                IoOp::Fdsync(1) => assert_eq!(res.ok(), Some(0)),
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+
+        assert!(io.preadv(&file, vec![vec![0; 60], vec![0; 60]], 10, 2).is_ok());
+        assert!(io.pread(&FD(-1), vec![0; 10], 0, 3).is_ok());
+        assert_eq!(io.submit().ok(), Some(2));
+        for (op, res) in io.results(2, 10, Some(Duration::from_secs(1))).unwrap() {
+            match op {
+                IoOp::Preadv(bufv, 2) => {
+                    assert_eq!(res.ok(), Some(90));
+                    assert_eq!(&bufv[1][..30], &[b'x'; 30][..]);
+                },
+                IoOp::Pread(_, 3) => assert_eq!(res.err().and_then(|e| e.raw_os_error()), Some(::libc::EBADF)),
+                op => panic!("unexpected {:?}", op),
+            }
+        }
+    }
+
+    #[test]
+    fn threadpool_eventfd() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, Threadpool::new(1).unwrap());
+        let file = tmpfile("poolevfd");
+        let mut pfd = ::libc::pollfd { fd: io.eventfd().unwrap().as_raw_fd(), events: ::libc::POLLIN, revents: 0 };
+
+        assert!(io.pwrite(&file, vec![b'x'; 10], 0, 0).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+
+        assert_eq!(unsafe { ::libc::poll(&mut pfd, 1, 1000) }, 1);
+        assert_eq!(io.try_results(10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn threadpool_cancel() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, Threadpool::new(1).unwrap());
+        let file = tmpfile("poolcancel");
+        let mut fds = [0; 2];
+
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // Poll ties up the only worker
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 0).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        let h = io.pwrite(&file, vec![0; 10], 0, 1).unwrap();
+        assert_eq!(io.submit().ok(), Some(1));
+
+        match io.cancel(h) {
+            Ok(IoOp::Pwrite(_, 1)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        assert_eq!(unsafe { ::libc::write(fds[1], b"x".as_ptr() as *const _, 1) }, 1);
+        let res = io.results(1, 10, Some(Duration::from_secs(1))).unwrap();
+        match res[0] {
+            (IoOp::Poll(0), Ok(ev)) => assert!(ev & ::libc::POLLIN as usize != 0),
+            ref r => panic!("unexpected {:?}", r),
+        }
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]) };
+    }
+
+    #[test]
+    fn auto_cancel() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, Auto::new(10, 1).unwrap());
+        let file = tmpfile("autocancel");
+
+        // Stop the only worker, so pool operations stay queued
+        {
+            let pool = &mut io.backend_mut().pool;
+
+            pool.shared.state.lock().unwrap().shutdown = true;
+            pool.shared.work.notify_all();
+            for w in pool.workers.drain(..) {
+                w.join().unwrap();
+            }
+        }
+
+        let h = io.pwrite(&file, vec![0; 10], 0, 0).unwrap();
+        assert_eq!(io.submit().ok(), Some(1));
+        assert_eq!(io.backend().inflight(), (0, 1));
+
+        // With the file closed it looks like a kernel operation
+        drop(file);
+        match io.cancel(h) {
+            Ok(IoOp::Pwrite(_, 0)) => (),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert_eq!(io.backend().inflight(), (0, 0));
+    }
+
+    #[test]
+    fn auto() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, Auto::new(10, 2).unwrap());
+        let file = tmpfile("auto");
+        let mut fds = [0; 2];
+
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        // Buffered file goes to the pool, poll to the kernel
+        assert!(io.pwrite(&file, vec![b'x'; 10], 0, 0).is_ok());
+        assert!(io.poll(&FD(fds[0]), ::libc::POLLIN, 1).is_ok());
+        assert_eq!(io.submit().ok(), Some(2));
+        assert_eq!(io.backend().inflight(), (1, 1));
+
+        assert_eq!(io.results(1, 10, Some(Duration::from_secs(1))).unwrap().len(), 1);
+        assert_eq!(io.backend().inflight(), (1, 0));
+
+        assert_eq!(unsafe { ::libc::write(fds[1], b"x".as_ptr() as *const _, 1) }, 1);
+        assert_eq!(io.results(1, 10, Some(Duration::from_secs(1))).unwrap().len(), 1);
+        assert_eq!(io.backend().inflight(), (0, 0));
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]) };
+    }
+}
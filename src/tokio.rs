@@ -0,0 +1,226 @@
+//! Tokio integration.
+//!
+//! This is the `future` interface with its reaper run as a tokio
+//! task rather than a thread: the context's eventfd is registered
+//! with tokio's reactor, and completions are reaped whenever it
+//! becomes readable. The futures can be awaited from any task.
+//!
+//! Needs the `tokio` feature, and a runtime with IO enabled.
+extern crate std;
+extern crate tokio as rt;
+
+use std::io;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::os::unix::io::{AsRawFd, RawFd};
+use self::rt::io::unix::AsyncFd;
+use self::rt::io::Interest;
+use self::rt::runtime::Handle;
+use self::rt::task::JoinHandle;
+
+use buf::{RdBuf, WrBuf};
+use future::{self, IoFuture, SyncFuture};
+use raw::SubmitPolicy;
+use super::Offset;
+
+// Duplicate of the context's eventfd, which stays open for as long
+// as it's registered with the reactor, even once the context has been
+// shut down.
+struct Evfd(RawFd);
+
+impl Evfd {
+    fn dup<F: AsRawFd>(fd: &F) -> io::Result<Evfd> {
+        let fd = unsafe { ::libc::fcntl(fd.as_raw_fd(), ::libc::F_DUPFD_CLOEXEC, 0) };
+
+        if fd < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Evfd(fd))
+        }
+    }
+}
+
+impl AsRawFd for Evfd {
+    fn as_raw_fd(&self) -> RawFd { self.0 }
+}
+
+impl Drop for Evfd {
+    fn drop(&mut self) {
+        unsafe { ::libc::close(self.0) };
+    }
+}
+
+// Task which reaps completions whenever the eventfd is readable.
+struct Reaper<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> {
+    evfd: AsyncFd<Evfd>,
+    ctx: Arc<future::Iocontext<Wb, Rb>>,
+}
+
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> Future for Reaper<Wb, Rb> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        loop {
+            match self.evfd.poll_read_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(_)) => return Poll::Ready(()),
+                Poll::Ready(Ok(mut guard)) => {
+                    // reap drains the eventfd, so it's no longer ready
+                    guard.clear_ready();
+                    let _ = self.ctx.reap();
+                },
+            }
+        }
+    }
+}
+
+/// Tokio AIO context.
+///
+/// This is like `future::Iocontext`, except that completions are
+/// reaped by a task on the current tokio runtime. Dropping the
+/// context stops the task, then cancels or waits for outstanding
+/// operations, as for `future::Iocontext`. That's done synchronously
+/// by whatever drops it, rather than left to the task, since the
+/// runtime might never run the task again; so it may block.
+pub struct Iocontext<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> {
+    ctx: Arc<future::Iocontext<Wb, Rb>>,
+    reaper: JoinHandle<()>,
+}
+
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> Iocontext<Wb, Rb> {
+    /// Construct a new Iocontext with up to `maxops` outstanding
+    /// operations, reaped by a task on the current runtime. Fails if
+    /// there's no current runtime, or it doesn't have IO enabled.
+    pub fn new(maxops: usize) -> io::Result<Iocontext<Wb, Rb>> {
+        let handle = match Handle::try_current() {
+            Err(e) => return Err(io::Error::other(e)),
+            Ok(h) => h,
+        };
+        let ctx = match future::Iocontext::manual(maxops) {
+            Err(e) => return Err(e),
+            Ok(ctx) => Arc::new(ctx),
+        };
+        let evfd = AsyncFd::with_interest(Evfd::dup(&*ctx)?, Interest::READABLE)?;
+        let reaper = handle.spawn(Reaper { evfd, ctx: ctx.clone() });
+
+        Ok(Iocontext { ctx, reaper })
+    }
+
+    /// Submit all batched operations. See `raw::Iocontext::submit`.
+    pub fn submit(&self) -> io::Result<usize> { self.ctx.submit() }
+
+    /// Set when operations are submitted automatically. See
+    /// `future::Iocontext::set_submit_policy`.
+    pub fn set_submit_policy(&self, policy: SubmitPolicy) { self.ctx.set_submit_policy(policy) }
+
+    /// Number of operations whose futures haven't been resolved.
+    pub fn pending(&self) -> usize { self.ctx.pending() }
+
+    /// Queue a pread.
+    pub fn pread<F: AsRawFd>(&self, file: &F, buf: Rb, off: Offset) -> IoFuture<Rb> {
+        self.ctx.pread(file, buf, off)
+    }
+
+    /// Queue a preadv.
+    pub fn preadv<F: AsRawFd>(&self, file: &F, bufv: Vec<Rb>, off: Offset) -> IoFuture<Vec<Rb>> {
+        self.ctx.preadv(file, bufv, off)
+    }
+
+    /// Queue a pwrite.
+    pub fn pwrite<F: AsRawFd>(&self, file: &F, buf: Wb, off: Offset) -> IoFuture<Wb> {
+        self.ctx.pwrite(file, buf, off)
+    }
+
+    /// Queue a pwritev.
+    pub fn pwritev<F: AsRawFd>(&self, file: &F, bufv: Vec<Wb>, off: Offset) -> IoFuture<Vec<Wb>> {
+        self.ctx.pwritev(file, bufv, off)
+    }
+
+    /// Queue an fsync.
+    pub fn fsync<F: AsRawFd>(&self, file: &F) -> SyncFuture { self.ctx.fsync(file) }
+
+    /// Queue an fdatasync.
+    pub fn fdsync<F: AsRawFd>(&self, file: &F) -> SyncFuture { self.ctx.fdsync(file) }
+}
+
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> AsRawFd for Iocontext<Wb, Rb> {
+    fn as_raw_fd(&self) -> RawFd { self.ctx.as_raw_fd() }
+}
+
+impl<Wb: WrBuf + Send + 'static, Rb: RdBuf + Send + 'static> Drop for Iocontext<Wb, Rb> {
+    fn drop(&mut self) {
+        // The task keeps its reference to the context until the
+        // runtime gets round to dropping it, which may be never
+        self.reaper.abort();
+        self.ctx.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Waker};
+    use super::rt::runtime::Builder;
+    use super::Iocontext;
+
+    fn tmpfile(name: &str) -> File {
+        let tmp = TempDir::new("test").unwrap();
+        let mut path = tmp.into_path();
+
+        path.push(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    #[test]
+    fn tokio() {
+        let rt = Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = rt.enter();
+        let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+        let file = tmpfile("tokio");
+
+        let (res, _) = rt.block_on(io.pwrite(&file, vec![b'x'; 40], 0));
+        assert_eq!(res.ok(), Some(40));
+        assert!(rt.block_on(io.fdsync(&file)).is_ok());
+
+        let r1 = io.pread(&file, vec![0; 20], 0);
+        let r2 = io.pread(&file, vec![0; 20], 20);
+        let (res, buf) = rt.block_on(r2);
+        assert_eq!(res.ok(), Some(20));
+        assert_eq!(buf, vec![b'x'; 20]);
+        assert_eq!(rt.block_on(r1).0.ok(), Some(20));
+        assert_eq!(io.pending(), 0);
+    }
+
+    #[test]
+    fn drop_undriven() {
+        let rt = Builder::new_current_thread().enable_io().build().unwrap();
+        let _guard = rt.enter();
+        let io : Iocontext<Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+        let file = tmpfile("undriven");
+
+        let mut fut = io.pwrite(&file, vec![b'x'; 40], 0);
+        assert!(io.submit().is_ok());
+
+        // Nothing runs the reaper task, so only the drop resolves it
+        drop(io);
+        let mut cx = Context::from_waker(Waker::noop());
+        assert!(Pin::new(&mut fut).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn no_runtime() {
+        assert!(Iocontext::<Vec<u8>, Vec<u8>>::new(10).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+//! io_uring backend.
+//!
+//! `Uring` performs the operations queued on a `raw::Iocontext` with
+//! io_uring rather than kernel AIO, translating each iocb into a
+//! submission queue entry. `Native` uses it if the running kernel
+//! supports it, and kernel AIO otherwise; with the `uring` feature
+//! enabled it's the default backend for `raw::Iocontext::new`.
+//!
+//! Linux 5.5 or later is needed. Like the rest of the crate, this
+//! calls the syscalls directly rather than using liburing.
+extern crate std;
+
+use std::io;
+use std::ptr;
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Instant;
+use libc::{c_int, c_long, c_uint, c_void};
+
+use aioabi as aio;
+use backend::{Backend, Kernel};
+use pool::Pool;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+// Submission queue entry
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,             // rw_flags, fsync_flags, poll events, etc
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+// Completion queue entry
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+
+const IORING_FEAT_NODROP: u32 = 1 << 1;
+
+const IORING_ENTER_GETEVENTS: c_uint = 1 << 0;
+
+const IORING_REGISTER_EVENTFD: c_uint = 4;
+const IORING_UNREGISTER_EVENTFD: c_uint = 5;
+
+const IORING_FSYNC_DATASYNC: u32 = 1 << 0;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_OP_ASYNC_CANCEL: u8 = 14;
+
+// user_data for internal requests, whose completions are ignored
+const TIMEOUT_DATA: u64 = !0;
+const INTERNAL_DATA: u64 = !0 - 1;
+
+// A mapping of part of the ring.
+struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: c_int, len: usize, off: i64) -> io::Result<Mmap> {
+        let ptr = unsafe {
+            ::libc::mmap(ptr::null_mut(), len, ::libc::PROT_READ | ::libc::PROT_WRITE,
+                         ::libc::MAP_SHARED | ::libc::MAP_POPULATE, fd, off as ::libc::off_t)
+        };
+
+        if ptr == ::libc::MAP_FAILED {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Mmap { ptr: ptr as *mut u8, len })
+        }
+    }
+
+    unsafe fn at<T>(&self, off: u32) -> *mut T { self.ptr.offset(off as isize) as *mut T }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { ::libc::munmap(self.ptr as *mut c_void, self.len) };
+    }
+}
+
+// An operation in flight. The iovec is here so it stays put until
+// the operation completes.
+struct Slot {
+    iocb: *mut aio::Struct_iocb,
+    iov: aio::Struct_iovec,
+}
+
+/// io_uring backend. See `Native` for how it differs from kernel AIO.
+pub struct Uring {
+    fd: c_int,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    params: Params,
+    slots: Pool<Slot>,
+    eventfd: Option<c_int>,     // registered eventfd
+}
+
+// Nothing in the rings is tied to a thread.
+unsafe impl Send for Uring {}
+
+fn setup(entries: u32, params: &mut Params) -> c_int {
+    unsafe { ::libc::syscall(::libc::SYS_io_uring_setup, entries as c_long, params as *mut Params) as c_int }
+}
+
+impl Uring {
+    /// Set up an io_uring for up to `maxops` outstanding operations.
+    /// Fails if the kernel doesn't support io_uring, or is too old.
+    pub fn new(maxops: usize) -> io::Result<Uring> {
+        let mut params = Params::default();
+
+        // Leave room for internal timeout and cancel requests
+        let fd = setup(maxops as u32 + 2, &mut params);
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        // Close the ring if anything else fails
+        struct Fd(c_int);
+        impl Drop for Fd {
+            fn drop(&mut self) { unsafe { ::libc::close(self.0) }; }
+        }
+        let guard = Fd(fd);
+
+        if params.features & IORING_FEAT_NODROP == 0 {
+            return Err(io::Error::from_raw_os_error(::libc::ENOSYS))
+        }
+
+        let sq = Mmap::new(fd, (params.sq_off.array + params.sq_entries * 4) as usize, IORING_OFF_SQ_RING)?;
+        let cq = Mmap::new(fd, params.cq_off.cqes as usize + params.cq_entries as usize * mem::size_of::<Cqe>(),
+                           IORING_OFF_CQ_RING)?;
+        let sqes = Mmap::new(fd, params.sq_entries as usize * mem::size_of::<Sqe>(), IORING_OFF_SQES)?;
+
+        mem::forget(guard);
+        Ok(Uring {
+            fd, sq, cq, sqes, params,
+            slots: Pool::new(std::cmp::max(maxops, 1)),
+            eventfd: None,
+        })
+    }
+
+    unsafe fn sq_head(&self) -> &AtomicU32 { &*self.sq.at(self.params.sq_off.head) }
+    unsafe fn sq_tail(&self) -> &AtomicU32 { &*self.sq.at(self.params.sq_off.tail) }
+    unsafe fn cq_head(&self) -> &AtomicU32 { &*self.cq.at(self.params.cq_off.head) }
+    unsafe fn cq_tail(&self) -> &AtomicU32 { &*self.cq.at(self.params.cq_off.tail) }
+
+    // Number of entries queued but not yet consumed by the kernel.
+    fn sq_pending(&self) -> u32 {
+        unsafe { self.sq_tail().load(Ordering::Relaxed).wrapping_sub(self.sq_head().load(Ordering::Acquire)) }
+    }
+
+    // Add an entry to the submission queue. Returns false if it's full.
+    fn push(&mut self, sqe: Sqe) -> bool {
+        if self.sq_pending() >= self.params.sq_entries {
+            return false
+        }
+
+        unsafe {
+            let tail = self.sq_tail().load(Ordering::Relaxed);
+            let idx = tail & *self.sq.at::<u32>(self.params.sq_off.ring_mask);
+
+            *self.sqes.at::<Sqe>(0).offset(idx as isize) = sqe;
+            *self.sq.at::<u32>(self.params.sq_off.array).offset(idx as isize) = idx;
+            // Make the entry visible before the new tail
+            self.sq_tail().store(tail.wrapping_add(1), Ordering::Release);
+        }
+        true
+    }
+
+    // Submit queued entries and optionally wait for a completion.
+    fn enter(&mut self, wait: bool) -> io::Result<usize> {
+        loop {
+            let r = unsafe {
+                ::libc::syscall(::libc::SYS_io_uring_enter, self.fd as c_long, self.sq_pending() as c_long,
+                                if wait { 1 } else { 0 } as c_long,
+                                if wait { IORING_ENTER_GETEVENTS } else { 0 } as c_long,
+                                ptr::null::<c_void>(), 0 as c_long)
+            };
+
+            if r >= 0 {
+                return Ok(r as usize)
+            }
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(::libc::EINTR) {
+                return Err(e)
+            }
+        }
+    }
+
+    // Register `fd` as the ring's eventfd, or unregister it if `None`.
+    fn register_eventfd(&mut self, fd: Option<c_int>) -> io::Result<()> {
+        let r = unsafe {
+            match fd {
+                None => ::libc::syscall(::libc::SYS_io_uring_register, self.fd as c_long,
+                                        IORING_UNREGISTER_EVENTFD as c_long, ptr::null::<c_void>(), 0 as c_long),
+                Some(ref efd) => ::libc::syscall(::libc::SYS_io_uring_register, self.fd as c_long,
+                                                 IORING_REGISTER_EVENTFD as c_long, efd as *const c_int, 1 as c_long),
+            }
+        };
+
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            self.eventfd = fd;
+            Ok(())
+        }
+    }
+
+    // Translate an iocb into an entry, or return the errno to reject
+    // it with.
+    unsafe fn sqe(&mut self, iocb: *mut aio::Struct_iocb) -> Result<Sqe, i32> {
+        let cb = &*iocb;
+        let op = cb.aio_lio_opcode;
+        let mut sqe = Sqe {
+            fd: cb.aio_fildes as i32,
+            off: cb.aio_offset,
+            ioprio: if cb.aio_flags & aio::IOCB_FLAG_IOPRIO != 0 { cb.aio_reqprio } else { 0 },
+            ..Default::default()
+        };
+
+        let single = op == aio::Iocmd::IO_CMD_PREAD as u16 || op == aio::Iocmd::IO_CMD_PWRITE as u16;
+        let vectored = op == aio::Iocmd::IO_CMD_PREADV as u16 || op == aio::Iocmd::IO_CMD_PWRITEV as u16;
+
+        if single || vectored {
+            let read = op == aio::Iocmd::IO_CMD_PREAD as u16 || op == aio::Iocmd::IO_CMD_PREADV as u16;
+
+            sqe.opcode = if read { IORING_OP_READV } else { IORING_OP_WRITEV };
+            sqe.op_flags = cb.aio_rw_flags;
+            if vectored {
+                sqe.addr = cb.aio_buf;
+                sqe.len = cb.aio_count as u32;
+            } else {
+                sqe.len = 1;    // addr is set to the slot's iovec
+            }
+        } else if op == aio::Iocmd::IO_CMD_FSYNC as u16 {
+            sqe.opcode = IORING_OP_FSYNC;
+        } else if op == aio::Iocmd::IO_CMD_FDSYNC as u16 {
+            sqe.opcode = IORING_OP_FSYNC;
+            sqe.op_flags = IORING_FSYNC_DATASYNC;
+        } else if op == aio::Iocmd::IO_CMD_POLL as u16 {
+            // poll32_events; the same as the old 16-bit field on little-endian
+            sqe.opcode = IORING_OP_POLL_ADD;
+            sqe.op_flags = cb.aio_buf as u16 as u32;
+        } else {
+            return Err(::libc::EINVAL)
+        }
+
+        let slot = Slot { iocb,
+                          iov: aio::Struct_iovec { iov_base: cb.aio_buf as *mut u8, iov_len: cb.aio_count as ::libc::size_t } };
+        let idx = match self.slots.allocidx(slot) {
+            Err(_) => return Err(::libc::EAGAIN),
+            Ok(idx) => idx,
+        };
+        if single {
+            sqe.addr = &self.slots[idx].iov as *const aio::Struct_iovec as u64;
+        }
+        sqe.user_data = idx as u64;
+
+        Ok(sqe)
+    }
+
+    // Move completions into `events`, skipping internal ones.
+    fn reap_cq(&mut self, events: &mut [aio::Struct_io_event]) -> usize {
+        let mut n = 0;
+
+        unsafe {
+            let mask = *self.cq.at::<u32>(self.params.cq_off.ring_mask);
+            let cqes = self.cq.at::<Cqe>(self.params.cq_off.cqes);
+            let tail = self.cq_tail().load(Ordering::Acquire);
+            let mut head = self.cq_head().load(Ordering::Relaxed);
+
+            while n < events.len() && head != tail {
+                let cqe = ptr::read_volatile(cqes.offset((head & mask) as isize));
+                head = head.wrapping_add(1);
+
+                match cqe.user_data {
+                    TIMEOUT_DATA | INTERNAL_DATA => (),
+                    idx => {
+                        let slot = self.slots.freeidx(idx as usize);
+
+                        events[n] = aio::Struct_io_event { data: (*slot.iocb).data, obj: slot.iocb as u64,
+                                                           res: cqe.res as i64, res2: 0 };
+                        n += 1;
+                    },
+                }
+            }
+
+            self.cq_head().store(head, Ordering::Release);
+        }
+
+        n
+    }
+}
+
+impl Backend for Uring {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        let mut n = 0;
+
+        for &iocb in iocbs {
+            let sqe = match self.sqe(iocb) {
+                Err(errno) => if n == 0 { return Err(io::Error::from_raw_os_error(errno)) } else { break },
+                Ok(sqe) => sqe,
+            };
+            if !self.push(sqe) {
+                self.slots.freeidx(sqe.user_data as usize);
+                break
+            }
+            n += 1;
+        }
+
+        if n == 0 && !iocbs.is_empty() {
+            return Err(io::Error::from_raw_os_error(::libc::EAGAIN))
+        }
+
+        match self.enter(false) {
+            // Anything the kernel doesn't take now is still queued, and
+            // goes with the next enter
+            Err(ref e) if e.raw_os_error() == Some(::libc::EAGAIN) || e.raw_os_error() == Some(::libc::EBUSY) => Ok(n),
+            Err(e) => {
+                // Take back whatever of ours the kernel didn't consume,
+                // so it isn't submitted after it's been failed
+                let left = std::cmp::min(n as u32, self.sq_pending());
+                unsafe {
+                    let tail = self.sq_tail().load(Ordering::Relaxed).wrapping_sub(left);
+                    let mask = *self.sq.at::<u32>(self.params.sq_off.ring_mask);
+
+                    for i in 0..left {
+                        let idx = tail.wrapping_add(i) & mask;
+                        self.slots.freeidx((*self.sqes.at::<Sqe>(0).offset(idx as isize)).user_data as usize);
+                    }
+                    self.sq_tail().store(tail, Ordering::Release);
+                }
+
+                match n - left as usize {
+                    0 => Err(e),
+                    n => Ok(n),
+                }
+            },
+            Ok(_) => Ok(n),
+        }
+    }
+
+    // Cancellation is asynchronous: the operation completes with
+    // ECANCELED if it's cancelled.
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        let idx = (0..self.slots.limit()).find(|&i| self.slots.get(i).is_some_and(|s| s.iocb == iocb));
+
+        match idx {
+            None => Err(io::Error::from_raw_os_error(::libc::EINVAL)),
+            Some(idx) => {
+                let sqe = Sqe { opcode: IORING_OP_ASYNC_CANCEL, fd: -1, addr: idx as u64,
+                                user_data: INTERNAL_DATA, ..Default::default() };
+                if !self.push(sqe) {
+                    return Err(io::Error::from_raw_os_error(::libc::EAGAIN))
+                }
+                let _ = self.enter(false);
+                Err(io::Error::from_raw_os_error(::libc::EINPROGRESS))
+            },
+        }
+    }
+
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        let mut n = self.reap_cq(events);
+
+        while n < min && n < events.len() {
+            // The timeout completes at the deadline or with the next
+            // completion, whichever is first, so it never outlives
+            // the wait by much.
+            let ts;
+            if let Some(d) = deadline {
+                let now = Instant::now();
+
+                if d <= now {
+                    break
+                }
+                let left = d - now;
+
+                ts = KernelTimespec { tv_sec: left.as_secs() as i64, tv_nsec: left.subsec_nanos() as i64 };
+                let sqe = Sqe { opcode: IORING_OP_TIMEOUT, fd: -1, addr: &ts as *const KernelTimespec as u64,
+                                len: 1, off: 1, user_data: TIMEOUT_DATA, ..Default::default() };
+                if !self.push(sqe) {
+                    if n > 0 {
+                        break
+                    }
+                    return Err(io::Error::from_raw_os_error(::libc::EAGAIN))
+                }
+            }
+
+            if let Err(e) = self.enter(true) {
+                if n == 0 {
+                    return Err(e)
+                }
+                break
+            }
+            n += self.reap_cq(&mut events[n..]);
+        }
+
+        Ok(n)
+    }
+
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        if self.sq_pending() > 0 {
+            let _ = self.enter(false);
+        }
+        Some(self.reap_cq(events))
+    }
+
+    // The eventfd is registered once rather than following each
+    // iocb's RESFD flag: registering quiesces the ring on kernels
+    // before 5.17. Every completion signals it from then on.
+    fn set_eventfd(&mut self, fd: RawFd) -> io::Result<()> {
+        if self.eventfd == Some(fd) {
+            return Ok(())
+        }
+        if self.eventfd.is_some() {
+            self.register_eventfd(None)?;
+        }
+        self.register_eventfd(Some(fd))
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        // The rings stay mapped until the fields are dropped
+        unsafe { ::libc::close(self.fd) };
+    }
+}
+
+/// Backend which uses io_uring if the kernel supports it, and kernel
+/// AIO otherwise.
+///
+/// The two don't behave quite the same. With io_uring:
+///
+/// * operations in a batch may be performed in any order, whereas
+///   kernel AIO on buffered files performs them in order at submission;
+/// * a bad file descriptor fails the operation when it completes,
+///   rather than when it's submitted;
+/// * `cancel` is always asynchronous, returning `EINPROGRESS`, and the
+///   operation completes with `ECANCELED` if it could be cancelled.
+///   More kinds of operation can be cancelled than with kernel AIO;
+/// * once the context has an eventfd, every completion signals it,
+///   including those of operations queued before it was created and
+///   of the backend's own internal timeouts and cancellations. It can
+///   be readable when there's nothing to reap.
+// There's only one per context, so the size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub enum Native {
+    /// io_uring
+    Uring(Uring),
+    /// Kernel AIO
+    Aio(Kernel),
+}
+
+impl Native {
+    /// Set up a backend for up to `maxops` outstanding operations.
+    pub fn new(maxops: usize) -> io::Result<Native> {
+        match Uring::new(maxops) {
+            Ok(u) => Ok(Native::Uring(u)),
+            Err(_) => Kernel::new(maxops).map(Native::Aio),
+        }
+    }
+
+    /// Returns true if io_uring is being used.
+    pub fn is_uring(&self) -> bool {
+        match *self {
+            Native::Uring(_) => true,
+            Native::Aio(_) => false,
+        }
+    }
+}
+
+impl Backend for Native {
+    unsafe fn submit(&mut self, iocbs: &[*mut aio::Struct_iocb]) -> io::Result<usize> {
+        match *self {
+            Native::Uring(ref mut u) => u.submit(iocbs),
+            Native::Aio(ref mut k) => k.submit(iocbs),
+        }
+    }
+
+    unsafe fn cancel(&mut self, iocb: *mut aio::Struct_iocb) -> io::Result<()> {
+        match *self {
+            Native::Uring(ref mut u) => u.cancel(iocb),
+            Native::Aio(ref mut k) => k.cancel(iocb),
+        }
+    }
+
+    fn getevents(&mut self, min: usize, events: &mut [aio::Struct_io_event], deadline: Option<Instant>)
+                 -> io::Result<usize> {
+        match *self {
+            Native::Uring(ref mut u) => u.getevents(min, events, deadline),
+            Native::Aio(ref mut k) => k.getevents(min, events, deadline),
+        }
+    }
+
+    fn reap(&mut self, events: &mut [aio::Struct_io_event]) -> Option<usize> {
+        match *self {
+            Native::Uring(ref mut u) => u.reap(events),
+            Native::Aio(ref mut k) => k.reap(events),
+        }
+    }
+
+    fn set_eventfd(&mut self, fd: RawFd) -> io::Result<()> {
+        match *self {
+            Native::Uring(ref mut u) => u.set_eventfd(fd),
+            Native::Aio(ref mut k) => k.set_eventfd(fd),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate tempdir;
+
+    use self::tempdir::TempDir;
+    use std::fs::{File, OpenOptions};
+    use std::mem;
+    use std::time::{Duration, Instant};
+    use raw::{Iocontext, IoOp};
+    use super::super::FD;
+    use super::{Uring, Sqe, Cqe, Params};
+
+    fn tmpfile(name: &str) -> File {
+        let tmp = TempDir::new("test").unwrap();
+        let mut path = tmp.into_path();
+
+        path.push(name);
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path).unwrap()
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(mem::size_of::<Sqe>(), 64);
+        assert_eq!(mem::size_of::<Cqe>(), 16);
+        assert_eq!(mem::size_of::<Params>(), 120);
+    }
+
+    // Default context uses whatever's available
+    #[test]
+    fn native() {
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>> = Iocontext::new(10).unwrap();
+        let file = tmpfile("native");
+
+        assert!(io.pwrite(&file, vec![b'x'; 10], 0, 0).is_ok());
+        assert_eq!(io.submit().ok(), Some(1));
+        assert_eq!(io.results(1, 10, None).unwrap()[0].1.as_ref().ok(), Some(&10));
+    }
+
+    #[test]
+    fn uring() {
+        let u = match Uring::new(10) {
+            Err(e) => { println!("no io_uring: {}", e); return },
+            Ok(u) => u,
+        };
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, u);
+        let file = tmpfile("uring");
+
+        assert!(io.pwrite(&file, vec![b'x'; 100], 0, 0).is_ok());
+        assert!(io.pwritev(&file, vec![vec![b'y'; 10], vec![b'z'; 10]], 100, 1).is_ok());
+        assert_eq!(io.submit().ok(), Some(2));
+        let res = io.results(2, 10, Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(res.len(), 2);
+        assert!(res.iter().all(|(_, r)| r.is_ok()));
+
+        assert!(io.fdsync(&file, 2).is_ok());
+        assert!(io.pread(&file, vec![0; 200], 0, 3).is_ok());
+        assert!(io.pread(&FD(-1), vec![0; 10], 0, 4).is_ok());
+        assert_eq!(io.submit().ok(), Some(3));
+
+        let mut got = 0;
+        while io.pending() > 0 {
+            for (op, res) in io.results(1, 10, Some(Duration::from_secs(1))).unwrap() {
+                match op {
+                    IoOp::Fdsync(2) => assert_eq!(res.ok(), Some(0)),
+                    IoOp::Pread(buf, 3) => {
+                        assert_eq!(res.ok(), Some(120));
+                        assert_eq!(&buf[100..120], &b"yyyyyyyyyyzzzzzzzzzz"[..]);
+                    },
+                    IoOp::Pread(_, 4) => assert_eq!(res.err().and_then(|e| e.raw_os_error()), Some(::libc::EBADF)),
+                    op => panic!("unexpected {:?}", op),
+                }
+                got += 1;
+            }
+        }
+        assert_eq!(got, 3);
+    }
+
+    #[test]
+    fn uring_timeout_cancel() {
+        let u = match Uring::new(10) {
+            Err(e) => { println!("no io_uring: {}", e); return },
+            Ok(u) => u,
+        };
+        let mut io : Iocontext<usize, Vec<u8>, Vec<u8>, _> = Iocontext::with_backend(10, u);
+        let mut fds = [0; 2];
+
+        assert_eq!(unsafe { ::libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let h = io.poll(&FD(fds[0]), ::libc::POLLIN, 0).unwrap();
+        assert_eq!(io.submit().ok(), Some(1));
+
+        let start = Instant::now();
+        assert_eq!(io.results(1, 10, Some(Duration::from_millis(50))).unwrap().len(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        assert_eq!(io.cancel(h).err().and_then(|e| e.raw_os_error()), Some(::libc::EINPROGRESS));
+        match io.results(1, 10, Some(Duration::from_secs(1))).unwrap().pop() {
+            Some((IoOp::Poll(0), Err(e))) => assert_eq!(e.raw_os_error(), Some(::libc::ECANCELED)),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        unsafe { ::libc::close(fds[0]); ::libc::close(fds[1]) };
+    }
+}